@@ -19,7 +19,8 @@ pub fn send_reply(args: &Args, message: &str) -> Result<(), Error> {
     Ok(())
 }
 
-fn response_exists(args: &Args) -> Option<MessageId> {
+/// The bot's response to the command message, if it already replied
+pub fn response_exists(args: &Args) -> Option<MessageId> {
     let data = args.cx.data.read();
     let history = data.get::<CommandHistory>().unwrap();
     history.get(&args.msg.id).copied()
@@ -9,20 +9,75 @@ impl TypeMapKey for CommandHistory {
 }
 
 pub fn replay_message(cx: Context, ev: MessageUpdateEvent, cmds: &Commands) -> Result<(), Error> {
-    if let (Some(created), Some(edited)) = (ev.timestamp, ev.edited_timestamp) {
+    // Updates without content are e.g. embeds being added, not edits
+    if let (Some(created), Some(edited), Some(content)) =
+        (ev.timestamp, ev.edited_timestamp, ev.content)
+    {
         // Only track edits for recent messages
         if (edited - created).num_minutes() < 60 {
             let mut msg = CustomMessage::new();
-            msg.id(ev.id)
-                .channel_id(ev.channel_id)
-                .content(ev.content.unwrap_or_else(String::new));
-            cmds.execute(&cx, &msg.build());
+            msg.id(ev.id).channel_id(ev.channel_id).content(content);
+            if let Some(author) = ev.author {
+                msg.author(author);
+            }
+            let msg = msg.build();
+
+            // If the message isn't a command anymore, the old response would be stale
+            if !cmds.is_command(&cx, &msg.content) {
+                let response_id = cx
+                    .data
+                    .write()
+                    .get_mut::<CommandHistory>()
+                    .unwrap()
+                    .shift_remove(&msg.id);
+                if let Some(response_id) = response_id {
+                    info!("deleting stale response: {:?}", response_id);
+                    msg.channel_id.delete_message(&cx, response_id)?;
+                }
+                return Ok(());
+            }
+
+            cmds.replay(&cx, &msg);
         }
     }
 
     Ok(())
 }
 
+/// Re-run a command when its author reacts with 🔁 on the bot's reply. Only replies that are still
+/// in the command history can be re-run, so this only works for recent commands
+pub fn rerun_on_reaction(cx: Context, reaction: Reaction, cmds: &Commands) -> Result<(), Error> {
+    if reaction.emoji != ReactionType::Unicode("🔁".into()) {
+        return Ok(());
+    }
+
+    let command_message_id = {
+        let data = cx.data.read();
+        let history = data.get::<CommandHistory>().unwrap();
+        match history
+            .iter()
+            .find(|(_, &response_id)| response_id == reaction.message_id)
+        {
+            Some((&command_message_id, _)) => command_message_id,
+            None => return Ok(()),
+        }
+    };
+
+    let msg = reaction.channel_id.message(&cx, command_message_id)?;
+    if msg.author.id != reaction.user_id {
+        return Ok(());
+    }
+
+    // Remove the reaction so the command can be re-run again. This needs the Manage Messages
+    // permission, which the bot may not have
+    if let Err(e) = reaction.delete(&cx) {
+        warn!("Can't remove rerun reaction: {}", e);
+    }
+
+    cmds.rerun(&cx, &msg);
+    Ok(())
+}
+
 pub fn clear_command_history(cx: &Context) -> Result<(), Error> {
     let mut data = cx.data.write();
     let history = data.get_mut::<CommandHistory>().unwrap();
@@ -1,7 +1,9 @@
 use crate::Error;
 use reqwest::blocking::Client as HttpClient;
 use serenity::{model::prelude::*, prelude::*};
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
+use std::hash::Hash;
+use std::time::{Duration, Instant};
 
 pub const PREFIXES: &[&str] = &[
     "?",
@@ -19,8 +21,306 @@ pub const PREFIXES: &[&str] = &[
     "hey fewwis, can you ",
 ];
 
+/// How long users have to wait between two invocations of the same rate limited command
+const COMMAND_COOLDOWN: Duration = Duration::from_secs(3);
+
+/// How a command came to be executed
+#[derive(Clone, Copy, PartialEq)]
+enum Invocation {
+    /// A new message
+    New,
+    /// An edit of a message that was already executed
+    Edit,
+    /// A 🔁 reaction on the reply
+    Rerun,
+}
+
+/// When users last invoked the rate limited commands
+pub struct CommandCooldowns;
+
+impl TypeMapKey for CommandCooldowns {
+    type Value = HashMap<(UserId, &'static str), Instant>;
+}
+
+/// Record an invocation at `now`, unless the last invocation with the same key was less than
+/// `cooldown` ago. In that case, returns how much longer the cooldown lasts. Expired entries are
+/// removed along the way
+///
+/// ```rust
+/// let mut last_invocations = HashMap::new();
+/// let cooldown = Duration::from_secs(3);
+/// let start = Instant::now();
+/// assert_eq!(try_start_cooldown(&mut last_invocations, "a", start, cooldown), Ok(()));
+/// assert_eq!(try_start_cooldown(&mut last_invocations, "b", start, cooldown), Ok(()));
+///
+/// let later = start + Duration::from_secs(1);
+/// assert_eq!(
+///     try_start_cooldown(&mut last_invocations, "a", later, cooldown),
+///     Err(Duration::from_secs(2)),
+/// );
+///
+/// // rejected invocations don't extend the cooldown
+/// let later = start + Duration::from_secs(3);
+/// assert_eq!(try_start_cooldown(&mut last_invocations, "a", later, cooldown), Ok(()));
+/// assert_eq!(last_invocations.len(), 1);
+/// ```
+fn try_start_cooldown<K: Eq + Hash>(
+    last_invocations: &mut HashMap<K, Instant>,
+    key: K,
+    now: Instant,
+    cooldown: Duration,
+) -> Result<(), Duration> {
+    last_invocations.retain(|_, last_invocation| now.duration_since(*last_invocation) < cooldown);
+    if let Some(last_invocation) = last_invocations.get(&key) {
+        return Err(cooldown - now.duration_since(*last_invocation));
+    }
+    last_invocations.insert(key, now);
+    Ok(())
+}
+
+/// Usage counters since the bot started. Kept in memory only for now
+pub struct UsageStats {
+    pub started: Instant,
+    /// How often each command was invoked, including by edits
+    pub command_invocations: HashMap<&'static str, u64>,
+    /// Requests sent to the playground, not counting cached responses
+    pub playground_requests: u64,
+}
+
+impl UsageStats {
+    pub fn new() -> Self {
+        Self {
+            started: Instant::now(),
+            command_invocations: HashMap::new(),
+            playground_requests: 0,
+        }
+    }
+}
+
+impl TypeMapKey for UsageStats {
+    type Value = Self;
+}
+
+pub fn stats(args: &Args) -> Result<(), Error> {
+    let reply = {
+        let data = args.cx.data.read();
+        let stats = data.get::<UsageStats>().unwrap();
+
+        let uptime = stats.started.elapsed().as_secs();
+        let mut reply = format!(
+            "Since {}d {}h {}m ago:\n```\n",
+            uptime / 86400,
+            uptime / 3600 % 24,
+            uptime / 60 % 60
+        );
+
+        let mut invocations = stats.command_invocations.iter().collect::<Vec<_>>();
+        invocations.sort_by(|a, b| b.1.cmp(a.1).then(a.0.cmp(b.0)));
+        for (command, count) in invocations {
+            reply += &format!("?{:<12}{}\n", command, count);
+        }
+        reply += &format!("\nPlayground requests: {}\n```", stats.playground_requests);
+        reply
+    };
+
+    // send_reply locks the data for writing, so the read lock has to be released by now
+    crate::api::send_reply(args, &reply)
+}
+
+pub fn stats_help(args: &Args) -> Result<(), Error> {
+    crate::api::send_reply(
+        args,
+        "Show how often each command was used and how many requests were sent to the playground, \
+        since the bot started.
+```?stats```",
+    )
+}
+
+/// Additional command names configured by the mods with `?alias`, persisted to disk
+pub struct CommandAliases {
+    path: String,
+    /// lowercase alias -> name of the command it invokes
+    aliases: HashMap<String, String>,
+}
+
+impl TypeMapKey for CommandAliases {
+    type Value = CommandAliases;
+}
+
+impl CommandAliases {
+    /// Load the aliases from the given file, or start without any if the file doesn't exist yet
+    pub fn load(path: String) -> Result<Self, Error> {
+        let aliases = match std::fs::read_to_string(&path) {
+            Ok(json) => serde_json::from_str(&json)?,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => HashMap::new(),
+            Err(e) => return Err(e.into()),
+        };
+        Ok(Self { path, aliases })
+    }
+
+    fn get(&self, alias: &str) -> Option<&str> {
+        self.aliases
+            .get(&alias.to_ascii_lowercase())
+            .map(|target| target.as_str())
+    }
+
+    fn insert(&mut self, alias: &str, target: &str) -> Result<(), Error> {
+        self.aliases
+            .insert(alias.to_ascii_lowercase(), target.to_owned());
+        self.save()
+    }
+
+    fn remove(&mut self, alias: &str) -> Result<bool, Error> {
+        let removed = self.aliases.remove(&alias.to_ascii_lowercase()).is_some();
+        self.save()?;
+        Ok(removed)
+    }
+
+    fn save(&self) -> Result<(), Error> {
+        std::fs::write(&self.path, serde_json::to_string(&self.aliases)?)?;
+        Ok(())
+    }
+}
+
+/// Commands that can't be disabled with `?disable`, so that the mods can't lock themselves out
+const ALWAYS_ENABLED_COMMANDS: &[&str] =
+    &["help", "disable", "enable", "cleanup", "kick", "timeout"];
+
+/// Commands that the mods disabled per channel with `?disable`, persisted to disk
+pub struct DisabledCommands {
+    path: String,
+    /// channel ID -> names of the commands disabled there
+    disabled: HashMap<u64, HashSet<String>>,
+}
+
+impl TypeMapKey for DisabledCommands {
+    type Value = DisabledCommands;
+}
+
+impl DisabledCommands {
+    /// Load the disabled commands from the given file, or start with all commands enabled if the
+    /// file doesn't exist yet
+    pub fn load(path: String) -> Result<Self, Error> {
+        let disabled = match std::fs::read_to_string(&path) {
+            Ok(json) => serde_json::from_str(&json)?,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => HashMap::new(),
+            Err(e) => return Err(e.into()),
+        };
+        Ok(Self { path, disabled })
+    }
+
+    fn is_disabled(&self, channel_id: ChannelId, command_name: &str) -> bool {
+        match self.disabled.get(&channel_id.0) {
+            Some(commands) => commands.contains(command_name),
+            None => false,
+        }
+    }
+
+    /// Disable or enable the command in the channel. Returns false if it already was
+    fn set_enabled(
+        &mut self,
+        channel_id: ChannelId,
+        command_name: &str,
+        enabled: bool,
+    ) -> Result<bool, Error> {
+        let commands = self.disabled.entry(channel_id.0).or_default();
+        let changed = match enabled {
+            true => commands.remove(command_name),
+            false => commands.insert(command_name.to_owned()),
+        };
+        if commands.is_empty() {
+            self.disabled.remove(&channel_id.0);
+        }
+        self.save()?;
+        Ok(changed)
+    }
+
+    fn save(&self) -> Result<(), Error> {
+        std::fs::write(&self.path, serde_json::to_string(&self.disabled)?)?;
+        Ok(())
+    }
+}
+
+const REPOSITORY_URL: &str = "https://github.com/kangalioo/rustbot";
+
+/// Command name -> source file with its implementation. The links don't point to a line, because
+/// the deployed bot isn't necessarily built from the latest commit
+const COMMAND_SOURCES: &[(&str, &str)] = &[
+    ("help", "commands.rs"),
+    ("alias", "commands.rs"),
+    ("disable", "commands.rs"),
+    ("enable", "commands.rs"),
+    ("crate", "crates.rs"),
+    ("docs", "crates.rs"),
+    ("doc", "crates.rs"),
+    ("play", "playground.rs"),
+    ("eval", "playground.rs"),
+    ("playwarn", "playground.rs"),
+    ("test", "playground.rs"),
+    ("asm", "playground.rs"),
+    ("llvmir", "playground.rs"),
+    ("mir", "playground.rs"),
+    ("shorten", "playground.rs"),
+    ("diff", "playground.rs"),
+    ("miri", "playground.rs"),
+    ("expand", "playground.rs"),
+    ("check", "playground.rs"),
+    ("whatcompiles", "playground.rs"),
+    ("clippy", "playground.rs"),
+    ("fmt", "playground.rs"),
+    ("microbench", "playground.rs"),
+    ("crates", "playground.rs"),
+    ("versions", "playground.rs"),
+    ("last", "playground.rs"),
+    ("explain", "playground.rs"),
+    ("go", "main.rs"),
+    ("godbolt", "godbolt.rs"),
+    ("godboltlink", "playground.rs"),
+    ("cleanup", "moderation.rs"),
+    ("kick", "moderation.rs"),
+    ("timeout", "moderation.rs"),
+    ("ban", "moderation.rs"),
+    ("banne", "moderation.rs"),
+    ("unban", "moderation.rs"),
+    ("banboard", "moderation.rs"),
+    ("stats", "commands.rs"),
+    ("source", "commands.rs"),
+];
+
+/// Link to the implementation of the given command, or None if it isn't known
+fn command_source_url(command_name: &str) -> Option<String> {
+    let (_, file_name) = COMMAND_SOURCES
+        .iter()
+        .find(|(name, _)| name.eq_ignore_ascii_case(command_name))?;
+    Some(format!("{}/blob/master/src/{}", REPOSITORY_URL, file_name))
+}
+
+pub fn source(args: &Args) -> Result<(), Error> {
+    let command_name = args.body.trim().trim_start_matches('?');
+    if command_name.is_empty() {
+        return crate::api::send_reply(args, REPOSITORY_URL);
+    }
+
+    match command_source_url(command_name) {
+        Some(url) => crate::api::send_reply(args, &url),
+        None => crate::api::send_reply(args, &format!("No such command `{}`", command_name)),
+    }
+}
+
+pub fn source_help(args: &Args) -> Result<(), Error> {
+    crate::api::send_reply(
+        args,
+        "?source [command]
+
+Links to the bot GitHub repo, or to the source file that implements the given command",
+    )
+}
+
 pub enum CommandHandler {
     Help,
+    Alias,
+    Disable,
+    Enable,
     Custom {
         action: Box<dyn Fn(&Args<'_>) -> Result<(), Error> + Send + Sync>,
         /// Multiline description of the command to display for the command-specific help command
@@ -31,7 +331,11 @@ pub enum CommandHandler {
 pub struct Command {
     pub name: &'static str,
     pub aliases: &'static [&'static str],
+    /// Whether to show the typing indicator before the command runs, for commands that wait on
+    /// slow requests. This is best-effort, the command still runs if it can't be shown
     pub broadcast_typing: bool,
+    /// Whether users have to wait [`COMMAND_COOLDOWN`] between invocations, except for mods
+    pub rate_limited: bool,
     /// Should be a short sentence to display inline in the help menu
     pub inline_help: &'static str,
     pub handler: CommandHandler,
@@ -43,24 +347,56 @@ pub struct Args<'a> {
     pub msg: &'a Message,
     pub params: HashMap<&'a str, &'a str>,
     pub body: &'a str,
+    /// Whether the command is re-run through a 🔁 reaction on its reply, in which case cached
+    /// results shouldn't be reused
+    pub is_rerun: bool,
 }
 
 pub struct Commands {
     client: HttpClient,
     commands: Vec<Command>,
+    mod_role_id: RoleId,
 }
 
 impl Commands {
-    pub fn new() -> Self {
+    pub fn new(mod_role_id: RoleId) -> Self {
         Self {
             client: HttpClient::new(),
-            commands: vec![Command {
-                name: "help",
-                aliases: &[],
-                broadcast_typing: false,
-                inline_help: "Show this menu",
-                handler: CommandHandler::Help,
-            }],
+            mod_role_id,
+            commands: vec![
+                Command {
+                    name: "help",
+                    aliases: &[],
+                    broadcast_typing: false,
+                    rate_limited: false,
+                    inline_help: "Show this menu",
+                    handler: CommandHandler::Help,
+                },
+                Command {
+                    name: "alias",
+                    aliases: &[],
+                    broadcast_typing: false,
+                    rate_limited: false,
+                    inline_help: "List or configure additional command names",
+                    handler: CommandHandler::Alias,
+                },
+                Command {
+                    name: "disable",
+                    aliases: &[],
+                    broadcast_typing: false,
+                    rate_limited: false,
+                    inline_help: "Disable a command in this channel",
+                    handler: CommandHandler::Disable,
+                },
+                Command {
+                    name: "enable",
+                    aliases: &[],
+                    broadcast_typing: false,
+                    rate_limited: false,
+                    inline_help: "Enable a command again in this channel",
+                    handler: CommandHandler::Enable,
+                },
+            ],
         }
     }
 
@@ -75,6 +411,7 @@ impl Commands {
             name: command,
             aliases: &[],
             broadcast_typing: false,
+            rate_limited: false,
             inline_help,
             handler: CommandHandler::Custom {
                 action: Box::new(handler),
@@ -84,6 +421,15 @@ impl Commands {
         self.commands.last_mut().unwrap()
     }
 
+    /// Make users wait between invocations of these commands
+    pub fn rate_limit(&mut self, command_names: &[&str]) {
+        for command in &mut self.commands {
+            if command_names.contains(&command.name) {
+                command.rate_limited = true;
+            }
+        }
+    }
+
     pub fn help_menu(&self, args: &Args) -> Result<(), Error> {
         if args.body.is_empty() {
             let mut menu = "```\nCommands:\n".to_owned();
@@ -92,13 +438,32 @@ impl Commands {
             }
             menu += "\nType ?help command for more info on a command.";
             menu += "\nYou can edit your message to the bot and the bot will edit its response.";
-            menu += "\n```";
+            menu += "\nReact with 🔁 to the bot's response to run your command again.";
 
-            crate::api::send_reply(args, &menu)
+            // With all the commands, the menu doesn't fit into a single message anymore
+            crate::reply_paginated_long_text(args, &menu, "\n```", "")
         } else {
-            match self.find_command(&args.body) {
+            match self.resolve_command(args.cx, &args.body) {
                 Some(cmd) => match &cmd.handler {
                     CommandHandler::Help => crate::api::send_reply(args, "Are you beyond help?"),
+                    CommandHandler::Alias => crate::api::send_reply(
+                        args,
+                        "?alias
+?alias add <name> <command>
+?alias remove <name>
+
+Lists the additional command names, or adds or removes one. Only mods can add and remove them. \
+Aliases must point to a command, not to another alias",
+                    ),
+                    CommandHandler::Disable | CommandHandler::Enable => crate::api::send_reply(
+                        args,
+                        "?disable
+?disable <command>
+?enable <command>
+
+Lists the commands that are disabled in this channel, or disables or enables a command in this \
+channel. Only mods can disable and enable commands. The moderation commands can't be disabled",
+                    ),
                     CommandHandler::Custom { help, .. } => (help)(args),
                 },
                 None => crate::api::send_reply(args, &format!("No such command `{}`", args.body)),
@@ -117,25 +482,252 @@ impl Commands {
         })
     }
 
+    /// Like [`Self::find_command`], but also considers the aliases configured with `?alias`
+    fn resolve_command<'a>(&'a self, cx: &Context, command_name: &str) -> Option<&'a Command> {
+        if let Some(command) = self.find_command(command_name) {
+            return Some(command);
+        }
+
+        let data = cx.data.read();
+        let target = data.get::<CommandAliases>()?.get(command_name)?;
+        // Aliases only ever point to commands, so they're never resolved any further
+        self.find_command(target)
+    }
+
+    fn is_mod(&self, msg: &Message) -> bool {
+        match &msg.member {
+            Some(member) => member.roles.contains(&self.mod_role_id),
+            None => false,
+        }
+    }
+
+    pub fn alias(&self, args: &Args) -> Result<(), Error> {
+        let mut parts = args.body.split_whitespace();
+        let (action, alias) = match (parts.next(), parts.next()) {
+            (None, _) => return self.list_aliases(args),
+            (Some(action), Some(alias)) => (action, alias),
+            (Some(_), None) => {
+                return crate::api::send_reply(args, "Usage: `?alias add <name> <command>`")
+            }
+        };
+
+        if !self.is_mod(args.msg) {
+            return crate::api::send_reply(args, "Only mods can configure aliases");
+        }
+
+        let reply = match action {
+            "add" => {
+                let target = match (parts.next(), parts.next()) {
+                    (Some(target), None) => target,
+                    _ => {
+                        return crate::api::send_reply(args, "Usage: `?alias add <name> <command>`")
+                    }
+                };
+                self.add_alias(args, alias, target)?
+            }
+            "remove" => {
+                let mut data = args.cx.data.write();
+                if data.get_mut::<CommandAliases>().unwrap().remove(alias)? {
+                    format!("Removed alias `{}`", alias)
+                } else {
+                    format!("There's no alias `{}`", alias)
+                }
+            }
+            _ => format!("Unknown action `{}`, expected `add` or `remove`", action),
+        };
+
+        // send_reply locks the data for writing, so no lock may be held by now
+        crate::api::send_reply(args, &reply)
+    }
+
+    fn add_alias(&self, args: &Args, alias: &str, target: &str) -> Result<String, Error> {
+        if !alias
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-')
+        {
+            return Ok(format!(
+                "`{}` isn't a valid alias, use only letters, digits, `_` and `-`",
+                alias
+            ));
+        }
+        if let Some(command) = self.find_command(alias) {
+            return Ok(format!("`{}` is already used by ?{}", alias, command.name));
+        }
+
+        let mut data = args.cx.data.write();
+        let aliases = data.get_mut::<CommandAliases>().unwrap();
+        let command = match self.find_command(target) {
+            Some(command) => command,
+            None if aliases.get(target).is_some() => {
+                return Ok(format!(
+                    "`{}` is an alias itself, point to the command directly instead",
+                    target
+                ))
+            }
+            None => return Ok(format!("No such command `{}`", target)),
+        };
+        aliases.insert(alias, command.name)?;
+
+        Ok(format!(
+            "?{} now runs ?{}",
+            alias.to_ascii_lowercase(),
+            command.name
+        ))
+    }
+
+    fn list_aliases(&self, args: &Args) -> Result<(), Error> {
+        let reply = {
+            let data = args.cx.data.read();
+            let mut aliases = data
+                .get::<CommandAliases>()
+                .unwrap()
+                .aliases
+                .iter()
+                .collect::<Vec<_>>();
+            aliases.sort();
+
+            if aliases.is_empty() {
+                "No aliases configured".to_owned()
+            } else {
+                let mut reply = "```\n".to_owned();
+                for (alias, target) in aliases {
+                    reply += &format!("?{:<12}?{}\n", alias, target);
+                }
+                reply + "```"
+            }
+        };
+
+        crate::api::send_reply(args, &reply)
+    }
+
+    pub fn disable(&self, args: &Args) -> Result<(), Error> {
+        self.set_enabled(args, false)
+    }
+
+    pub fn enable(&self, args: &Args) -> Result<(), Error> {
+        self.set_enabled(args, true)
+    }
+
+    fn set_enabled(&self, args: &Args, enabled: bool) -> Result<(), Error> {
+        if args.body.is_empty() && !enabled {
+            return self.list_disabled_commands(args);
+        }
+
+        let usage = match enabled {
+            true => "Usage: `?enable <command>`",
+            false => "Usage: `?disable <command>`",
+        };
+        let command_name = match args.body.split_whitespace().collect::<Vec<_>>().as_slice() {
+            [command_name] => command_name.trim_start_matches('?'),
+            _ => return crate::api::send_reply(args, usage),
+        };
+
+        if !self.is_mod(args.msg) {
+            return crate::api::send_reply(args, "Only mods can disable and enable commands");
+        }
+
+        let command = match self.resolve_command(args.cx, command_name) {
+            Some(command) => command,
+            None => {
+                return crate::api::send_reply(args, &format!("No such command `{}`", command_name))
+            }
+        };
+        if ALWAYS_ENABLED_COMMANDS.contains(&command.name) {
+            return crate::api::send_reply(args, &format!("?{} can't be disabled", command.name));
+        }
+
+        let changed = args
+            .cx
+            .data
+            .write()
+            .get_mut::<DisabledCommands>()
+            .unwrap()
+            .set_enabled(args.msg.channel_id, command.name, enabled)?;
+        let reply = match (enabled, changed) {
+            (true, true) => format!("Enabled ?{} in this channel", command.name),
+            (true, false) => format!("?{} isn't disabled in this channel", command.name),
+            (false, true) => format!("Disabled ?{} in this channel", command.name),
+            (false, false) => format!("?{} is already disabled in this channel", command.name),
+        };
+
+        // send_reply locks the data for writing, so no lock may be held by now
+        crate::api::send_reply(args, &reply)
+    }
+
+    fn list_disabled_commands(&self, args: &Args) -> Result<(), Error> {
+        let reply = {
+            let data = args.cx.data.read();
+            let mut commands = match data
+                .get::<DisabledCommands>()
+                .unwrap()
+                .disabled
+                .get(&args.msg.channel_id.0)
+            {
+                Some(commands) => commands.iter().collect::<Vec<_>>(),
+                None => Vec::new(),
+            };
+            commands.sort();
+
+            if commands.is_empty() {
+                "No commands are disabled in this channel".to_owned()
+            } else {
+                let commands = commands
+                    .iter()
+                    .map(|name| format!("?{}", name))
+                    .collect::<Vec<_>>();
+                format!("Disabled in this channel: {}", commands.join(", "))
+            }
+        };
+
+        crate::api::send_reply(args, &reply)
+    }
+
     pub fn execute(&self, cx: &Context, serenity_msg: &Message) {
+        self.run(cx, serenity_msg, Invocation::New);
+    }
+
+    /// Execute the edited command in `serenity_msg`, editing the existing reply
+    pub fn replay(&self, cx: &Context, serenity_msg: &Message) {
+        self.run(cx, serenity_msg, Invocation::Edit);
+    }
+
+    /// Execute the command in `serenity_msg` again, editing the existing reply
+    pub fn rerun(&self, cx: &Context, serenity_msg: &Message) {
+        self.run(cx, serenity_msg, Invocation::Rerun);
+    }
+
+    /// Whether `content` invokes one of the commands
+    pub fn is_command(&self, cx: &Context, content: &str) -> bool {
+        self.parse_invocation(cx, content).is_some()
+    }
+
+    /// Find the command that `content` invokes, and the rest of the message after the command name
+    fn parse_invocation<'a>(&self, cx: &Context, content: &'a str) -> Option<(&Command, &'a str)> {
         // find the first matching prefix and strip it
-        let msg = match PREFIXES
+        let msg = PREFIXES
             .iter()
-            .find_map(|prefix| serenity_msg.content.strip_prefix(prefix))
-        {
-            Some(x) => x,
-            None => return,
-        };
+            .find_map(|prefix| content.strip_prefix(prefix))?;
 
         // Find the command that matches this message
         let (command_name, msg) =
             msg.split_at(msg.find(char::is_whitespace).unwrap_or_else(|| msg.len()));
-        let msg = msg.trim();
-        let command = match self.find_command(command_name) {
+        let command = self.resolve_command(cx, command_name)?;
+        Some((command, msg.trim()))
+    }
+
+    fn run(&self, cx: &Context, serenity_msg: &Message, invocation: Invocation) {
+        let (command, msg) = match self.parse_invocation(cx, &serenity_msg.content) {
             Some(x) => x,
             None => return,
         };
 
+        let is_disabled = cx
+            .data
+            .read()
+            .get::<DisabledCommands>()
+            .unwrap()
+            .is_disabled(serenity_msg.channel_id, command.name);
+
         let mut params = HashMap::new();
         let mut body = "";
         for token in msg.split_whitespace() {
@@ -143,7 +735,7 @@ impl Commands {
             if let (Some(param_name), Some(param_val)) = (splitn_2.next(), splitn_2.next()) {
                 // Check that the param key is sensible, otherwise any equal sign in arg body
                 // (think ?eval) will be parsed as a parameter
-                if param_name.chars().all(|c| c.is_alphanumeric()) {
+                if param_name.chars().all(|c| c.is_alphanumeric() || c == '_') {
                     params.insert(param_name, param_val);
                     continue;
                 }
@@ -162,8 +754,51 @@ impl Commands {
             cx: &cx,
             msg: &serenity_msg,
             http: &self.client,
+            is_rerun: invocation == Invocation::Rerun,
         };
 
+        if is_disabled {
+            let reply = format!("?{} is disabled in this channel", command.name);
+            if let Err(e) = crate::api::send_reply(&args, &reply) {
+                error!("{}", e)
+            }
+            return;
+        }
+
+        // Edited and re-run messages have no member info to check for mods, and the cooldown
+        // would replace a perfectly fine reply with "Slow down"
+        if command.rate_limited && invocation == Invocation::New && !self.is_mod(serenity_msg) {
+            let cooldown = {
+                let mut data = cx.data.write();
+                let last_invocations = data.get_mut::<CommandCooldowns>().unwrap();
+                try_start_cooldown(
+                    last_invocations,
+                    (serenity_msg.author.id, command.name),
+                    Instant::now(),
+                    COMMAND_COOLDOWN,
+                )
+            };
+            if let Err(remaining) = cooldown {
+                let reply = format!(
+                    "Slow down, you can use ?{} again in {}s",
+                    command.name,
+                    remaining.as_secs_f64().ceil()
+                );
+                if let Err(e) = crate::api::send_reply(&args, &reply) {
+                    error!("{}", e)
+                }
+                return;
+            }
+        }
+
+        *cx.data
+            .write()
+            .get_mut::<UsageStats>()
+            .unwrap()
+            .command_invocations
+            .entry(command.name)
+            .or_insert(0) += 1;
+
         if command.broadcast_typing {
             if let Err(e) = serenity_msg.channel_id.broadcast_typing(&cx.http) {
                 warn!("Can't broadcast typing: {}", e);
@@ -172,6 +807,9 @@ impl Commands {
 
         let command_execution_result = match &command.handler {
             CommandHandler::Help => self.help_menu(&args),
+            CommandHandler::Alias => self.alias(&args),
+            CommandHandler::Disable => self.disable(&args),
+            CommandHandler::Enable => self.enable(&args),
             CommandHandler::Custom { action, .. } => (action)(&args),
         };
         if let Err(e) = command_execution_result {
@@ -3,7 +3,7 @@ use crate::{api, commands::Args, Error};
 use reqwest::header;
 use serde::Deserialize;
 
-const USER_AGENT: &str = "rust-lang/discord-mods-bot";
+pub const USER_AGENT: &str = "rust-lang/discord-mods-bot";
 
 #[derive(Debug, Deserialize)]
 struct Crates {
@@ -88,7 +88,36 @@ fn rustc_crate_link(crate_name: &str) -> Option<&'static str> {
     }
 }
 
+/// Whether the query looks like a path such as `serde`, `std::vec::Vec` or `tokio::spawn`, so that
+/// arbitrary text doesn't end up in URLs
+///
+/// ```rust
+/// assert!(is_plausible_path("std::vec::Vec"));
+/// assert!(is_plausible_path("serde-json")); // crate names can contain dashes
+/// assert!(!is_plausible_path("std::"));
+/// assert!(!is_plausible_path("foo bar"));
+/// assert!(!is_plausible_path("a/b?c"));
+/// ```
+fn is_plausible_path(query: &str) -> bool {
+    query.split("::").all(|segment| {
+        !segment.is_empty()
+            && segment
+                .chars()
+                .all(|c| c.is_alphanumeric() || c == '_' || c == '-')
+    })
+}
+
 pub fn doc_search(args: &Args) -> Result<(), Error> {
+    if !args.body.is_empty() && !is_plausible_path(args.body) {
+        return api::send_reply(
+            args,
+            &format!(
+                "`{}` doesn't look like a crate or item path, try something like `std::vec::Vec`",
+                args.body
+            ),
+        );
+    }
+
     let mut query_iter = args.body.splitn(2, "::");
     let crate_name = query_iter.next().unwrap();
 
@@ -63,7 +63,7 @@ fn compile_rust_source(
 }
 
 pub fn godbolt(args: &crate::Args) -> Result<(), crate::Error> {
-    let (lang, text) = match compile_rust_source(args.http, crate::extract_code(&args.body)?)? {
+    let (lang, text) = match compile_rust_source(args.http, &crate::extract_code(&args.body)?)? {
         Compilation::Success { asm, stderr } => ("x86asm", format!("{}\n{}", stderr, asm)),
         Compilation::Error { stderr } => ("rust", stderr),
     };
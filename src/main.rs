@@ -11,6 +11,7 @@ mod playground;
 
 use commands::{Args, Commands};
 use serenity::{model::prelude::*, prelude::*};
+use std::borrow::Cow;
 
 pub type Error = Box<dyn std::error::Error + Send + Sync>;
 
@@ -18,17 +19,42 @@ pub type Error = Box<dyn std::error::Error + Send + Sync>;
 struct Config {
     discord_token: String,
     mod_role_id: u64,
+    #[serde(default = "default_ban_board_path")]
+    ban_board_path: String,
+    #[serde(default = "default_aliases_path")]
+    aliases_path: String,
+    #[serde(default = "default_disabled_commands_path")]
+    disabled_commands_path: String,
+    /// If set, replies with more lines are cut off with a link to the full output, instead of
+    /// being split over several messages
+    max_reply_lines: Option<usize>,
+}
+
+fn default_ban_board_path() -> String {
+    "ban_board.json".to_owned()
+}
+
+fn default_aliases_path() -> String {
+    "aliases.json".to_owned()
+}
+
+fn default_disabled_commands_path() -> String {
+    "disabled_commands.json".to_owned()
 }
 
 fn app() -> Result<(), Error> {
     let Config {
         discord_token,
         mod_role_id,
+        ban_board_path,
+        aliases_path,
+        disabled_commands_path,
+        max_reply_lines,
     } = envy::from_env::<Config>()?;
 
     info!("starting...");
 
-    let mut cmds = Commands::new();
+    let mut cmds = Commands::new(RoleId(mod_role_id));
 
     cmds.add(
         "crate",
@@ -63,6 +89,62 @@ fn app() -> Result<(), Error> {
     )
     .broadcast_typing = true;
 
+    cmds.add(
+        "playwarn",
+        playground::play_warn,
+        "Compile and run rust code, treating warnings as errors",
+        playground::play_warn_help,
+    )
+    .broadcast_typing = true;
+
+    cmds.add(
+        "test",
+        playground::test,
+        "Run tests in rust code in a playground",
+        playground::test_help,
+    )
+    .broadcast_typing = true;
+
+    cmds.add(
+        "asm",
+        playground::asm,
+        "View the assembly generated for rust code",
+        playground::asm_help,
+    )
+    .broadcast_typing = true;
+
+    cmds.add(
+        "llvmir",
+        playground::llvm_ir,
+        "View the LLVM IR generated for rust code",
+        playground::llvm_ir_help,
+    )
+    .broadcast_typing = true;
+
+    cmds.add(
+        "mir",
+        playground::mir,
+        "View the MIR generated for rust code",
+        playground::mir_help,
+    )
+    .broadcast_typing = true;
+
+    cmds.add(
+        "shorten",
+        playground::shorten,
+        "Get a shareable playground link for rust code",
+        playground::shorten_help,
+    )
+    .broadcast_typing = true;
+
+    cmds.add(
+        "diff",
+        playground::diff,
+        "Compare the output of rust code across two release channels",
+        playground::diff_help,
+    )
+    .broadcast_typing = true;
+
     cmds.add(
         "miri",
         playground::miri,
@@ -79,6 +161,22 @@ fn app() -> Result<(), Error> {
     )
     .broadcast_typing = true;
 
+    cmds.add(
+        "check",
+        playground::check,
+        "Check whether code compiles without running it",
+        playground::check_help,
+    )
+    .broadcast_typing = true;
+
+    cmds.add(
+        "whatcompiles",
+        playground::what_compiles,
+        "Check which editions the code compiles under",
+        playground::what_compiles_help,
+    )
+    .broadcast_typing = true;
+
     cmds.add(
         "clippy",
         playground::clippy,
@@ -103,6 +201,36 @@ fn app() -> Result<(), Error> {
     )
     .broadcast_typing = true;
 
+    cmds.add(
+        "crates",
+        playground::crates,
+        "List the crates available on the playground",
+        playground::crates_help,
+    )
+    .broadcast_typing = true;
+
+    cmds.add(
+        "versions",
+        playground::versions,
+        "Show the playground's toolchain versions",
+        playground::versions_help,
+    )
+    .broadcast_typing = true;
+
+    cmds.add(
+        "last",
+        playground::last,
+        "Send your most recent output again",
+        playground::last_help,
+    );
+
+    cmds.add(
+        "explain",
+        playground::explain,
+        "Explain a compiler error code",
+        playground::explain_help,
+    );
+
     cmds.add(
         "go",
         |args| api::send_reply(args, "No"),
@@ -118,6 +246,14 @@ fn app() -> Result<(), Error> {
     )
     .broadcast_typing = true;
 
+    cmds.add(
+        "godboltlink",
+        playground::godbolt_link,
+        "Create a Compiler Explorer link for the code",
+        playground::godbolt_link_help,
+    )
+    .broadcast_typing = true;
+
     cmds.add(
         "cleanup",
         move |args| moderation::cleanup(args, RoleId(mod_role_id)),
@@ -125,6 +261,20 @@ fn app() -> Result<(), Error> {
         moderation::cleanup_help,
     );
 
+    cmds.add(
+        "kick",
+        move |args| moderation::kick(args, RoleId(mod_role_id)),
+        "Kicks a member from the server",
+        moderation::kick_help,
+    );
+
+    cmds.add(
+        "timeout",
+        move |args| moderation::timeout(args, RoleId(mod_role_id)),
+        "Times out a member",
+        moderation::timeout_help,
+    );
+
     cmds.add(
         "ban",
         moderation::joke_ban,
@@ -133,17 +283,90 @@ fn app() -> Result<(), Error> {
     )
     .aliases = &["banne"];
 
+    cmds.add(
+        "unban",
+        moderation::joke_unban,
+        "Unbans another person",
+        moderation::joke_unban_help,
+    );
+
+    cmds.add(
+        "banboard",
+        moderation::ban_board,
+        "Shows who has been banned the most",
+        moderation::ban_board_help,
+    );
+
+    cmds.add(
+        "stats",
+        commands::stats,
+        "Shows how often the commands were used",
+        commands::stats_help,
+    );
+
     cmds.add(
         "source",
-        |args| api::send_reply(args, "https://github.com/kangalioo/rustbot"),
+        commands::source,
         "Links to the bot GitHub repo",
-        |args| api::send_reply(args, "?source\n\nLinks to the bot GitHub repo"),
+        commands::source_help,
     );
 
-    Client::new_with_extras(&discord_token, |e| e.event_handler(Events { cmds }))?.start()?;
+    // These send requests to the playground, which rate limits the bot if users spam them
+    cmds.rate_limit(&[
+        "play",
+        "eval",
+        "playwarn",
+        "test",
+        "asm",
+        "llvmir",
+        "mir",
+        "shorten",
+        "diff",
+        "miri",
+        "expand",
+        "clippy",
+        "check",
+        "whatcompiles",
+        "fmt",
+        "microbench",
+    ]);
+
+    let mut client = Client::new_with_extras(&discord_token, |e| e.event_handler(Events { cmds }))?;
+    {
+        let mut data = client.data.write();
+        data.insert::<moderation::JokeBanBoard>(moderation::JokeBanBoard::load(ban_board_path)?);
+        data.insert::<commands::CommandAliases>(commands::CommandAliases::load(aliases_path)?);
+        data.insert::<MaxReplyLines>(max_reply_lines);
+        data.insert::<commands::DisabledCommands>(commands::DisabledCommands::load(
+            disabled_commands_path,
+        )?);
+        // Inserted here instead of in `ready`, so that they survive reconnects
+        data.insert::<playground::PlaygroundCache>(indexmap::IndexMap::new());
+        data.insert::<playground::LastOutputs>(std::collections::HashMap::new());
+        data.insert::<commands::CommandCooldowns>(std::collections::HashMap::new());
+        data.insert::<commands::UsageStats>(commands::UsageStats::new());
+    }
+    client.start()?;
     Ok(())
 }
 
+/// Maximum number of lines in a single reply message before it counts as "too long"
+const MAX_OUTPUT_LINES: usize = 45;
+/// Maximum length of a Discord message. Discord counts characters, not bytes, so the lengths of
+/// replies have to be measured with `chars().count()`
+const DISCORD_MESSAGE_LIMIT: usize = 2000;
+
+/// The configured maximum number of lines of a reply, from the MAX_REPLY_LINES env variable
+struct MaxReplyLines;
+
+impl TypeMapKey for MaxReplyLines {
+    type Value = Option<usize>;
+}
+
+fn max_reply_lines(args: &Args) -> Option<usize> {
+    *args.cx.data.read().get::<MaxReplyLines>()?
+}
+
 /// Send a Discord reply message and truncate the message with a given truncation message if the
 /// text is too long. "Too long" means, it either goes beyond Discord's 2000 char message limit,
 /// or if the text_body has too many lines.
@@ -168,80 +391,565 @@ fn reply_potentially_long_text(
     text_end: &str,
     truncation_msg: &str,
 ) -> Result<(), Error> {
-    const MAX_OUTPUT_LINES: usize = 45;
+    let max_lines = match max_reply_lines(args) {
+        Some(max_reply_lines) => max_reply_lines.min(MAX_OUTPUT_LINES),
+        None => MAX_OUTPUT_LINES,
+    };
+    let msg = truncate_long_text(text_body, text_end, truncation_msg, max_lines);
+    api::send_reply(args, &msg)
+}
+
+/// The message that [`reply_potentially_long_text`] sends, with `text_body` cut off after
+/// `max_lines` lines or at the message limit. A code block in `text_body` that's cut off is closed
+/// before `text_end`
+///
+/// ```rust
+/// assert_eq!(truncate_long_text("```\na\nb", "\n```", "", 5), "```\na\nb\n```");
+///
+/// // short lines, but too many of them
+/// assert_eq!(
+///     truncate_long_text("```\na\nb\nc\nd", "\n```", "\ntoo long", 3),
+///     "```\na\nb\n```\ntoo long",
+/// );
+///
+/// // few lines, but too many characters
+/// let text = truncate_long_text(&"a".repeat(3000), "\n```", "\ntoo long", 3);
+/// assert_eq!(text.chars().count(), DISCORD_MESSAGE_LIMIT);
+/// assert!(text.ends_with("aaa\n```\ntoo long"));
+///
+/// // code blocks inside the text that are cut off are closed
+/// assert_eq!(
+///     truncate_long_text("text\n```\na\nb\n```\nmore text", "", "\ntoo long", 3),
+///     "text\n```\na\n```\ntoo long",
+/// );
+/// let text = truncate_long_text(&format!("```\n{}\n```", "a".repeat(3000)), "", "", 3);
+/// assert_eq!(text.chars().count(), DISCORD_MESSAGE_LIMIT);
+/// assert!(text.ends_with("aaa\n```"));
+/// ```
+fn truncate_long_text(
+    text_body: &str,
+    text_end: &str,
+    truncation_msg: &str,
+    max_lines: usize,
+) -> String {
+    const CLOSING_FENCE: &str = "\n```";
 
-    // check the 2000 char limit first, because otherwise we could produce a too large message
-    let msg = if text_body.len() + text_end.len() > 2000 {
-        // This is how long the text body may be at max to conform to Discord's limit
-        let available_space = 2000 - text_end.len() - truncation_msg.len();
+    let text_end_length = text_end.chars().count();
+    // Whether the truncated text body leaves a code block open that the full text body closes
+    let fence_count = text_body.matches("```").count();
+    let cuts_code_block =
+        |truncated_body: &str| truncated_body.matches("```").count() % 2 != fence_count % 2;
+
+    // check the char limit first, because otherwise we could produce a too large message
+    if text_body.chars().count() + text_end_length > DISCORD_MESSAGE_LIMIT {
+        // This is how many chars the text body may have at max to conform to Discord's limit
+        let available_space =
+            DISCORD_MESSAGE_LIMIT - text_end_length - truncation_msg.chars().count();
+        let cut_at = |available_space: usize| {
+            text_body
+                .char_indices()
+                .nth(available_space)
+                .map_or(text_body.len(), |(i, _)| i)
+        };
 
-        let mut cut_off_point = available_space;
-        while !text_body.is_char_boundary(cut_off_point) {
-            cut_off_point -= 1;
+        let mut truncated_body = &text_body[..cut_at(available_space)];
+        if cuts_code_block(truncated_body) {
+            truncated_body = &text_body[..cut_at(available_space - CLOSING_FENCE.len())];
         }
+        let closing_fence = if cuts_code_block(truncated_body) {
+            CLOSING_FENCE
+        } else {
+            ""
+        };
 
         format!(
-            "{}{}{}",
-            &text_body[..cut_off_point],
-            text_end,
-            truncation_msg
+            "{}{}{}{}",
+            truncated_body, closing_fence, text_end, truncation_msg
         )
-    } else if text_body.lines().count() > MAX_OUTPUT_LINES {
+    } else if text_body.lines().count() > max_lines {
+        let truncated_body = text_body
+            .lines()
+            .take(max_lines)
+            .collect::<Vec<_>>()
+            .join("\n");
+        let closing_fence = if cuts_code_block(&truncated_body) {
+            CLOSING_FENCE
+        } else {
+            ""
+        };
         format!(
-            "{}{}{}",
-            text_body
-                .lines()
-                .take(MAX_OUTPUT_LINES)
-                .collect::<Vec<_>>()
-                .join("\n"),
-            text_end,
-            truncation_msg,
+            "{}{}{}{}",
+            truncated_body, closing_fence, text_end, truncation_msg,
         )
     } else {
         format!("{}{}", text_body, text_end)
-    };
+    }
+}
 
-    api::send_reply(args, &msg)
+/// Like [`reply_potentially_long_text`], but text that doesn't fit into a single message is first
+/// split on line boundaries into up to `MAX_PAGES` messages, which are sent one after another.
+/// Code blocks that span a page break are closed at the end of the page and reopened with the
+/// same language at the start of the next one, so every message renders correctly on its own.
+///
+/// Only if the text doesn't fit into `MAX_PAGES` messages either, or has more lines than configured
+/// with MAX_REPLY_LINES, it's truncated like in [`reply_potentially_long_text`].
+fn reply_paginated_long_text(
+    args: &Args,
+    text_body: &str,
+    text_end: &str,
+    truncation_msg: &str,
+) -> Result<(), Error> {
+    const MAX_PAGES: usize = 3;
+
+    if let Some(max_reply_lines) = max_reply_lines(args) {
+        if text_body.lines().count() > max_reply_lines {
+            return reply_potentially_long_text(args, text_body, text_end, truncation_msg);
+        }
+    }
+
+    let text = format!("{}{}", text_body, text_end);
+    if text.chars().count() <= DISCORD_MESSAGE_LIMIT
+        && text_body.lines().count() <= MAX_OUTPUT_LINES
+    {
+        return api::send_reply(args, &text);
+    }
+
+    match paginate(&text) {
+        Some(pages) if pages.len() <= MAX_PAGES => {
+            let mut pages = pages.into_iter();
+            if let Some(first_page) = pages.next() {
+                api::send_reply(args, &first_page)?;
+            }
+            // Only the first page is registered as the response to the command, so when the
+            // command is edited, the remaining pages are sent anew
+            for page in pages {
+                args.msg.channel_id.say(args.cx, page)?;
+            }
+            Ok(())
+        }
+        _ => reply_potentially_long_text(args, text_body, text_end, truncation_msg),
+    }
+}
+
+/// Split text on line boundaries into chunks that each fit into a Discord message, closing and
+/// reopening code blocks at the chunk borders. Returns None if a single line is too long to fit
+/// into a message
+fn paginate(text: &str) -> Option<Vec<String>> {
+    const CLOSING_FENCE: &str = "```";
+
+    /// Returns the opening fence (e.g. "\`\`\`rust") of the code block that's open after the
+    /// given line, if any
+    fn fence_after_line(mut open_fence: Option<String>, mut line: &str) -> Option<String> {
+        while let Some(fence_start) = line.find("```") {
+            line = &line[(fence_start + 3)..];
+            open_fence = match open_fence {
+                Some(_) => None,
+                None => {
+                    let lang_end = line
+                        .find(|c: char| c.is_whitespace() || c == '`')
+                        .unwrap_or(line.len());
+                    Some(format!("```{}", &line[..lang_end]))
+                }
+            };
+        }
+        open_fence
+    }
+
+    let mut pages = Vec::new();
+    let mut page = String::new();
+    let mut page_chars = 0;
+    let mut page_lines = 0;
+    let mut open_fence = None;
+    for line in text.lines() {
+        let fence_after = fence_after_line(open_fence.clone(), line);
+        let closing_len = if fence_after.is_some() {
+            CLOSING_FENCE.len()
+        } else {
+            0
+        };
+
+        let line_chars = line.chars().count();
+        if !page.is_empty()
+            && (page_chars + line_chars + 1 + closing_len > DISCORD_MESSAGE_LIMIT
+                || page_lines >= MAX_OUTPUT_LINES)
+        {
+            if let Some(fence) = &open_fence {
+                page += CLOSING_FENCE;
+                pages.push(std::mem::take(&mut page));
+                page += fence;
+                page += "\n";
+                page_chars = fence.chars().count() + 1;
+                page_lines = 1;
+            } else {
+                pages.push(std::mem::take(&mut page));
+                page_chars = 0;
+                page_lines = 0;
+            }
+        }
+
+        page += line;
+        page += "\n";
+        page_chars += line_chars + 1;
+        page_lines += 1;
+        open_fence = fence_after;
+
+        if page_chars + closing_len > DISCORD_MESSAGE_LIMIT {
+            return None;
+        }
+    }
+    if !page.trim().is_empty() {
+        pages.push(page);
+    }
+
+    Some(pages)
 }
 
-/// Extract code from a Discord code block on a best-effort basis
+/// Extract code from a Discord code block on a best-effort basis. If there are multiple \`\`\`
+/// code blocks, their contents are concatenated in order, separated by newlines.
+///
+/// A \`\`\` code block spanning multiple lines is only closed by a fence at the start of a line,
+/// so that code containing markdown, like doc comments with examples, is kept intact. A fence at
+/// the start of a line that's followed by a language specifier opens a nested block instead.
+/// Characters from copy-pasting, like curly quotes, are cleaned up with [`normalize_pasted_code`]
 ///
 /// ```rust
-/// assert_eq!(extract_code("`hello`"), Some("hello"));
-/// assert_eq!(extract_code("`    hello `"), Some("hello"));
-/// assert_eq!(extract_code("``` hello ```"), Some("hello"));
-/// assert_eq!(extract_code("```rust hello ```"), Some("hello"));
-/// assert_eq!(extract_code("```rust\nhello\n```"), Some("hello"));
-/// assert_eq!(extract_code("``` rust\nhello\n```"), Some("rust\nhello"));
+/// assert_eq!(extract_code("`hello`").unwrap(), "hello");
+/// assert_eq!(extract_code("`    hello `").unwrap(), "hello");
+/// assert_eq!(extract_code("``` hello ```").unwrap(), "hello");
+/// assert_eq!(extract_code("```rust hello ```").unwrap(), "hello");
+/// assert_eq!(extract_code("```rust\nhello\n```").unwrap(), "hello");
+/// assert_eq!(extract_code("``` rust\nhello\n```").unwrap(), "rust\nhello");
+/// assert_eq!(extract_code("```rs\nhello\n```").unwrap(), "hello");
+/// assert_eq!(extract_code("```rust,ignore\nhello\n```").unwrap(), "hello");
+/// assert_eq!(extract_code("```foo;\nhello\n```").unwrap(), "foo;\nhello");
+/// assert_eq!(extract_code("```fn main() {}```").unwrap(), "fn main() {}");
+/// assert!(extract_code("no code here").is_err());
+/// assert_eq!(
+///     extract_code("```rust\na\n``` setup done, now ``` b ``` and ```\nc\n```").unwrap(),
+///     "a\nb\nc"
+/// );
+///
+/// assert_eq!(
+///     extract_code("```rust\n/// ```\n/// assert!(f());\n/// ```\nfn f() -> bool { true }\n```").unwrap(),
+///     "/// ```\n/// assert!(f());\n/// ```\nfn f() -> bool { true }"
+/// );
+/// assert_eq!(
+///     extract_code("```rust\nlet md = \"\n```rust\nfn f() {}\n```\n\";\n```").unwrap(),
+///     "let md = \"\n```rust\nfn f() {}\n```\n\";"
+/// );
+/// // without a fence at the start of a line, the first fence closes the block like on Discord
+/// assert_eq!(extract_code("```rust\nhello```").unwrap(), "hello");
+/// assert_eq!(
+///     extract_code("```toml\n[dependencies]\nrand = \"0.8\"\n```\n```rust\nhello\n```").unwrap(),
+///     "hello"
+/// );
 /// ```
-pub fn extract_code(input: &str) -> Result<&str, Error> {
-    fn inner(input: &str) -> Option<&str> {
+pub fn extract_code(input: &str) -> Result<Cow<'_, str>, Error> {
+    /// Returns the contents of all \`\`\` code blocks, with the language specifiers stripped
+    fn extract_code_blocks(mut input: &str) -> Vec<&str> {
+        let mut code_blocks = Vec::new();
+        while let Some(block_start) = input.find("```") {
+            let block = &input[(block_start + 3)..];
+            let block_end = match find_closing_fence(block) {
+                Some(x) => x,
+                None => break, // unclosed code block
+            };
+
+            // \`\`\`toml blocks hold the dependencies of the code, not code
+            let contents = &block[..block_end];
+            if !(contents.starts_with("toml\n") || contents.starts_with("toml\r\n")) {
+                code_blocks.push(strip_lang_specifier(contents).trim());
+            }
+
+            input = &block[(block_end + 3)..];
+        }
+        code_blocks
+    }
+
+    /// Find the fence closing the code block, given the text after the opening fence
+    fn find_closing_fence(block: &str) -> Option<usize> {
+        let first_fence = block.find("```")?;
+        if !block[..first_fence].contains('\n') {
+            return Some(first_fence);
+        }
+
+        let mut nesting_depth = 0;
+        let mut line_start = 0;
+        for line in block.split_inclusive('\n') {
+            let indented_line = line.trim_start();
+            if let Some(after_fence) = indented_line.strip_prefix("```") {
+                let after_fence = after_fence.trim_end();
+                let opens_nested_block = !after_fence.is_empty()
+                    && after_fence
+                        .chars()
+                        .all(|c| c.is_alphanumeric() || ",_-+".contains(c));
+                if opens_nested_block {
+                    nesting_depth += 1;
+                } else if nesting_depth == 0 {
+                    return Some(line_start + line.len() - indented_line.len());
+                } else {
+                    nesting_depth -= 1;
+                }
+            }
+            line_start += line.len();
+        }
+
+        Some(first_fence)
+    }
+
+    /// Like Discord, only treat the first word as language specifier if it directly follows the
+    /// opening fence and is followed by a newline, so a legitimate first line like `foo;` is
+    /// kept. On the same line as the code, only Rust specifiers (`rust`, `rs`, `rust,ignore`...)
+    /// are stripped, so that e.g. \`\`\`fn main() {}\`\`\` stays intact
+    fn strip_lang_specifier(block: &str) -> &str {
+        let specifier_end = block.find(char::is_whitespace).unwrap_or(block.len());
+        let specifier = &block[..specifier_end];
+        let rest = &block[specifier_end..];
+
+        let is_specifier = !specifier.is_empty()
+            && specifier
+                .chars()
+                .all(|c| c.is_alphanumeric() || ",_-+".contains(c));
+        let is_rust_specifier = match specifier.split(',').next() {
+            Some(lang) => lang == "rust" || lang == "rs",
+            None => false,
+        };
+
+        if is_specifier && (rest.starts_with(&['\n', '\r'][..]) || is_rust_specifier) {
+            rest
+        } else {
+            block
+        }
+    }
+
+    fn inner(input: &str) -> Option<Cow<'_, str>> {
         let input = input.trim();
 
-        let extracted_code = if input.starts_with("```") && input.ends_with("```") {
-            let code_starting_point = input.find(char::is_whitespace)?; // skip over lang specifier
-            let code_end_point = input.len() - 3;
+        let code_blocks = extract_code_blocks(input);
+        match code_blocks.as_slice() {
+            [] => {}
+            [code] => return Some(Cow::Borrowed(code)),
+            code_blocks => return Some(Cow::Owned(code_blocks.join("\n"))),
+        }
 
+        if input.starts_with('`') && input.ends_with('`') {
             // can't fail but you can never be too sure
-            input.get(code_starting_point..code_end_point)?
-        } else if input.starts_with('`') && input.ends_with('`') {
-            // can't fail but you can never be too sure
-            input.get(1..(input.len() - 1))?
+            Some(Cow::Borrowed(input.get(1..(input.len() - 1))?.trim()))
         } else {
-            return None;
-        };
-
-        Some(extracted_code.trim())
+            None
+        }
     }
 
-    Ok(inner(input).ok_or(
+    let code = inner(input).ok_or(
         "Missing code block. Please use the following markdown:
 \\`code here\\`
 or
 \\`\\`\\`rust
 code here
 \\`\\`\\`",
-    )?)
+    )?;
+    check_code_size(&code)?;
+    Ok(normalize_pasted_code(code))
+}
+
+/// Maximum size of code in bytes that's sent to the playground. Larger snippets are rejected
+/// before any request is made, instead of wasting one that the playground may reject anyway
+const MAX_CODE_SIZE: usize = 64 * 1024;
+
+/// Reject code above [`MAX_CODE_SIZE`]
+///
+/// ```rust
+/// assert!(check_code_size("fn main() {}").is_ok());
+/// let error = check_code_size(&"x".repeat(100 * 1024)).unwrap_err();
+/// assert_eq!(error.to_string(), "Snippet too large (100 KB), please trim it");
+/// ```
+fn check_code_size(code: &str) -> Result<(), Error> {
+    if code.len() > MAX_CODE_SIZE {
+        return Err(format!(
+            "Snippet too large ({} KB), please trim it",
+            code.len().div_ceil(1024)
+        )
+        .into());
+    }
+    Ok(())
+}
+
+/// Clean up the characters that sneak in when copying code from websites or chat apps and cause
+/// baffling compile errors: curly quotes become straight quotes, non-breaking spaces become normal
+/// spaces and zero-width characters are removed. The contents of string literals, char literals
+/// and comments are kept as they are, unless the string literal itself was opened by a curly quote
+///
+/// ```rust
+/// assert_eq!(normalize_pasted_code("println!(“hi”);".into()), "println!(\"hi\");");
+/// assert_eq!(normalize_pasted_code("let c = ‘a’;".into()), "let c = 'a';");
+/// assert_eq!(normalize_pasted_code("let\u{a0}x = 1;\u{200b}".into()), "let x = 1;");
+/// assert_eq!(normalize_pasted_code("fn f<’a>(x: &’a str) {}".into()), "fn f<'a>(x: &'a str) {}");
+///
+/// // intentional special characters in literals and comments are kept
+/// assert_eq!(normalize_pasted_code("\"“a”\u{a0}\"".into()), "\"“a”\u{a0}\"");
+/// assert_eq!(normalize_pasted_code("(\"\\\"“\", '\"', “b”)".into()), "(\"\\\"“\", '\"', \"b\")");
+/// assert_eq!(normalize_pasted_code("'\\'' == “c”".into()), "'\\'' == \"c\"");
+/// assert_eq!(
+///     normalize_pasted_code("// it’s “fine”\nlet s = “x”; /* “y” */".into()),
+///     "// it’s “fine”\nlet s = \"x\"; /* “y” */"
+/// );
+/// assert_eq!(normalize_pasted_code("“it’s”".into()), "\"it’s\"");
+///
+/// // raw strings end only at a quote followed by as many `#` as they started with
+/// assert_eq!(
+///     normalize_pasted_code(r##"(r#"say "“hi”""#, br"\’", “x”)"##.into()),
+///     r##"(r#"say "“hi”""#, br"\’", "x")"##
+/// );
+/// assert_eq!(normalize_pasted_code("for_r“a”".into()), "for_r\"a\"");
+/// ```
+fn normalize_pasted_code(code: Cow<'_, str>) -> Cow<'_, str> {
+    const ZERO_WIDTH_CHARS: &[char] = &['\u{200b}', '\u{200c}', '\u{200d}', '\u{2060}', '\u{feff}'];
+    const NBSP: char = '\u{a0}';
+    fn is_single_quote(c: char) -> bool {
+        matches!(c, '\'' | '‘' | '’')
+    }
+    fn is_double_quote(c: char) -> bool {
+        matches!(c, '"' | '“' | '”')
+    }
+    fn is_identifier_char(c: char) -> bool {
+        c.is_alphanumeric() || c == '_'
+    }
+    /// Whether an `r` between `before` and `after` starts a raw string like `r"…"`, `r#"…"#` or
+    /// `br"…"`, rather than being part of an identifier
+    fn starts_raw_string(before: &str, mut after: impl Iterator<Item = char>) -> bool {
+        let mut previous = before.chars().rev();
+        let at_word_start = match previous.next() {
+            Some('b') => !matches!(previous.next(), Some(c) if is_identifier_char(c)),
+            Some(c) => !is_identifier_char(c),
+            None => true,
+        };
+        at_word_start && after.find(|&c| c != '#') == Some('"')
+    }
+
+    if !code
+        .contains(|c| matches!(c, '‘' | '’' | '“' | '”' | NBSP) || ZERO_WIDTH_CHARS.contains(&c))
+    {
+        return code;
+    }
+
+    enum State {
+        Code,
+        String { opened_by_curly_quote: bool },
+        RawString { hashes: usize },
+        LineComment,
+        BlockComment,
+    }
+
+    let mut normalized = String::with_capacity(code.len());
+    let mut state = State::Code;
+    let mut chars = code.chars().peekable();
+    while let Some(c) = chars.next() {
+        match state {
+            State::Code => match c {
+                '"' => {
+                    state = State::String {
+                        opened_by_curly_quote: false,
+                    };
+                    normalized.push(c);
+                }
+                '“' | '”' => {
+                    state = State::String {
+                        opened_by_curly_quote: true,
+                    };
+                    normalized.push('"');
+                }
+                'r' if starts_raw_string(&normalized, chars.clone()) => {
+                    normalized.push(c);
+                    let mut hashes = 0;
+                    while chars.peek() == Some(&'#') {
+                        hashes += 1;
+                        normalized.extend(chars.next());
+                    }
+                    normalized.extend(chars.next());
+                    state = State::RawString { hashes };
+                }
+                '/' if chars.peek() == Some(&'/') => {
+                    state = State::LineComment;
+                    normalized.push(c);
+                }
+                '/' if chars.peek() == Some(&'*') => {
+                    state = State::BlockComment;
+                    normalized.push(c);
+                }
+                c if is_single_quote(c) => {
+                    normalized.push('\'');
+                    // Copy char literals as they are, so that e.g. '"' doesn't open a string.
+                    // Anything else is a lifetime
+                    let mut lookahead = chars.clone();
+                    match (lookahead.next(), lookahead.next()) {
+                        (Some('\\'), _) => {
+                            // escaped char literal like '\'' or '\u{1F980}'
+                            normalized.extend(chars.next());
+                            normalized.extend(chars.next());
+                            for c in chars.by_ref() {
+                                if is_single_quote(c) {
+                                    normalized.push('\'');
+                                    break;
+                                }
+                                normalized.push(c);
+                            }
+                        }
+                        (Some(literal), Some(closing_quote)) if is_single_quote(closing_quote) => {
+                            normalized.push(literal);
+                            normalized.push('\'');
+                            chars.next();
+                            chars.next();
+                        }
+                        _ => {}
+                    }
+                }
+                NBSP => normalized.push(' '),
+                c if ZERO_WIDTH_CHARS.contains(&c) => {}
+                c => normalized.push(c),
+            },
+            State::String {
+                opened_by_curly_quote,
+            } => match c {
+                '\\' => {
+                    normalized.push(c);
+                    normalized.extend(chars.next());
+                }
+                '"' => {
+                    state = State::Code;
+                    normalized.push(c);
+                }
+                c if opened_by_curly_quote && is_double_quote(c) => {
+                    state = State::Code;
+                    normalized.push('"');
+                }
+                c => normalized.push(c),
+            },
+            State::RawString { hashes } => {
+                normalized.push(c);
+                let mut lookahead = chars.clone();
+                if c == '"' && (0..hashes).all(|_| lookahead.next() == Some('#')) {
+                    normalized.extend(chars.by_ref().take(hashes));
+                    state = State::Code;
+                }
+            }
+            State::LineComment => {
+                if c == '\n' {
+                    state = State::Code;
+                }
+                normalized.push(c);
+            }
+            State::BlockComment => {
+                if c == '*' && chars.peek() == Some(&'/') {
+                    state = State::Code;
+                    normalized.push(c);
+                    normalized.extend(chars.next());
+                } else {
+                    normalized.push(c);
+                }
+            }
+        }
+    }
+    Cow::Owned(normalized)
 }
 
 pub fn find_custom_emoji(args: &Args, emoji_name: &str) -> Option<Emoji> {
@@ -324,6 +1032,12 @@ impl EventHandler for Events {
         }
     }
 
+    fn reaction_add(&self, cx: Context, reaction: Reaction) {
+        if let Err(e) = command_history::rerun_on_reaction(cx, reaction, &self.cmds) {
+            error!("{}", e);
+        }
+    }
+
     fn message_delete(&self, cx: Context, channel_id: ChannelId, message_id: MessageId) {
         let mut data = cx.data.write();
         let history = data.get_mut::<command_history::CommandHistory>().unwrap();
@@ -333,3 +1047,68 @@ impl EventHandler for Events {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn code_size_limit() {
+        assert!(check_code_size(&"x".repeat(MAX_CODE_SIZE)).is_ok());
+        assert_eq!(
+            check_code_size(&"x".repeat(MAX_CODE_SIZE + 1))
+                .unwrap_err()
+                .to_string(),
+            "Snippet too large (65 KB), please trim it",
+        );
+    }
+
+    #[test]
+    fn oversized_code_block_is_rejected() {
+        let message = format!("```rust\n{}\n```", "x".repeat(MAX_CODE_SIZE + 1));
+        assert_eq!(
+            extract_code(&message).unwrap_err().to_string(),
+            "Snippet too large (65 KB), please trim it",
+        );
+    }
+
+    #[test]
+    fn extract_code_concatenates_blocks() {
+        assert_eq!(
+            extract_code("```rust\na\n``` setup done, now ``` b ``` and ```\nc\n```").unwrap(),
+            "a\nb\nc",
+        );
+        assert_eq!(
+            extract_code("```toml\n[dependencies]\nrand = \"0.8\"\n```\n```rust\nhello\n```")
+                .unwrap(),
+            "hello",
+        );
+        assert!(extract_code("no code here").is_err());
+    }
+
+    #[test]
+    fn long_text_is_truncated_by_lines_and_length() {
+        assert_eq!(
+            truncate_long_text("```\na\nb", "\n```", "", 5),
+            "```\na\nb\n```"
+        );
+        assert_eq!(
+            truncate_long_text("```\na\nb\nc\nd", "\n```", "\ntoo long", 3),
+            "```\na\nb\n```\ntoo long",
+        );
+        let text = truncate_long_text(&"a".repeat(3000), "\n```", "\ntoo long", 3);
+        assert_eq!(text.chars().count(), DISCORD_MESSAGE_LIMIT);
+        assert!(text.ends_with("aaa\n```\ntoo long"));
+    }
+
+    #[test]
+    fn truncated_code_blocks_are_closed() {
+        assert_eq!(
+            truncate_long_text("text\n```\na\nb\n```\nmore text", "", "\ntoo long", 3),
+            "text\n```\na\n```\ntoo long",
+        );
+        let text = truncate_long_text(&format!("```\n{}\n```", "a".repeat(3000)), "", "", 3);
+        assert_eq!(text.chars().count(), DISCORD_MESSAGE_LIMIT);
+        assert!(text.ends_with("aaa\n```"));
+    }
+}
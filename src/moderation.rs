@@ -1,31 +1,156 @@
-use crate::{Args, Error};
-use serenity::model::prelude::*;
-use std::collections::HashMap;
+use crate::{command_history::CommandHistory, Args, Error};
+use serenity::{model::prelude::*, prelude::TypeMapKey};
+use std::collections::{HashMap, HashSet};
 
-pub fn cleanup(args: &Args, mod_role_id: RoleId) -> Result<(), Error> {
-    let num_messages = if args.body.is_empty() {
-        5
+const CLEANUP_USAGE: &str =
+    "Usage: `?cleanup [limit] [@member | member ID | member=name] [dryrun]`";
+
+/// The member lookup string of a `?cleanup` argument, if the argument is a mention, a user ID or
+/// has a `member=` prefix. Other words aren't treated as member names, so that something like
+/// `?cleanup all` gets the usage help instead of a confusing member lookup
+///
+/// ```rust
+/// assert_eq!(cleanup_member_token("<@123456>"), Some("<@123456>"));
+/// assert_eq!(cleanup_member_token("<@!123456>"), Some("<@!123456>"));
+/// assert_eq!(cleanup_member_token("160518747713437696"), Some("160518747713437696"));
+/// assert_eq!(cleanup_member_token("member=ferris"), Some("ferris"));
+/// assert_eq!(cleanup_member_token("member="), None);
+/// assert_eq!(cleanup_member_token("all"), None);
+/// assert_eq!(cleanup_member_token("5"), None);
+/// assert_eq!(cleanup_member_token("99999999999999999999999"), None);
+/// ```
+fn cleanup_member_token(token: &str) -> Option<&str> {
+    // Discord IDs are snowflakes, which have at least 17 digits nowadays
+    const MIN_ID_LENGTH: usize = 17;
+
+    if let Some(name) = token.strip_prefix("member=") {
+        return Some(name).filter(|name| !name.is_empty());
+    }
+    let is_mention = token.starts_with("<@") && token.ends_with('>');
+    let is_id = token.len() >= MIN_ID_LENGTH && token.parse::<u64>().is_ok();
+    if is_mention || is_id {
+        Some(token)
     } else {
-        args.body.parse::<usize>()?
-    };
+        None
+    }
+}
 
-    info!("Cleaning up {} messages", num_messages);
+/// Whether a `?cleanup` argument was meant as the message limit. Those which aren't valid limits
+/// get the usage help as reply, instead of being looked up as a member
+///
+/// ```rust
+/// assert!(looks_like_limit("5"));
+/// assert!(looks_like_limit("-5"));
+/// assert!(looks_like_limit("5.5"));
+/// assert!(looks_like_limit("99999999999999999999999"));
+/// assert!(!looks_like_limit("dryrun"));
+/// assert!(!looks_like_limit("<@123456>"));
+/// assert!(!looks_like_limit("2much"));
+/// ```
+fn looks_like_limit(token: &str) -> bool {
+    let digits = token.trim_start_matches(&['-', '+'][..]);
+    !digits.is_empty()
+        && digits
+            .chars()
+            .all(|c| c.is_ascii_digit() || c == '.' || c == ',')
+}
 
-    let is_mod = match &args.msg.member {
+/// Whether the author of the message has the mod role. Outside of servers, nobody is a mod
+fn is_mod(args: &Args, mod_role_id: RoleId) -> bool {
+    match &args.msg.member {
         Some(member) => member.roles.contains(&mod_role_id),
-        None => true, // in DMs, treat the user as an "effective" mod
-    };
+        None => false,
+    }
+}
+
+pub fn cleanup(args: &Args, mod_role_id: RoleId) -> Result<(), Error> {
+    let mut num_messages = 5;
+    let mut target_user = None;
+    let mut dry_run = false;
+    for token in args.body.split_whitespace() {
+        let member = cleanup_member_token(token);
+        if let (None, Ok(n)) = (member, token.parse::<usize>()) {
+            num_messages = n;
+            continue;
+        }
+        if token.eq_ignore_ascii_case("dryrun") {
+            dry_run = true;
+            continue;
+        }
+        if looks_like_limit(token) {
+            return crate::api::send_reply(
+                args,
+                &format!(
+                    "`{}` isn't a valid limit, it must be a positive whole number. {}",
+                    token, CLEANUP_USAGE
+                ),
+            );
+        }
+        let member = match member {
+            Some(x) => x,
+            None => {
+                return crate::api::send_reply(
+                    args,
+                    &format!("Unknown argument `{}`. {}", token, CLEANUP_USAGE),
+                )
+            }
+        };
+
+        let guild_id = match args.msg.guild_id {
+            Some(x) => x,
+            None => {
+                return crate::api::send_reply(
+                    args,
+                    &format!(
+                        "Filtering by user only works in a server. {}",
+                        CLEANUP_USAGE
+                    ),
+                )
+            }
+        };
+        target_user = match find_member_user(args, guild_id, member)? {
+            Some(x) => Some(x),
+            None => return Ok(()),
+        };
+    }
+
+    info!("Cleaning up {} messages", num_messages);
+
+    // in DMs, treat the user as an "effective" mod
+    let is_mod = args.msg.member.is_none() || is_mod(args, mod_role_id);
     let data = args.cx.data.read();
     let bot_id = *data.get::<crate::BotUserId>().unwrap();
 
-    args.msg
+    let messages = args
+        .msg
         .channel_id
-        .messages(&args.cx.http, |m| m.limit(100))?
+        .messages(&args.cx.http, |m| m.limit(100))?;
+
+    // The bot's responses to the commands of the target user
+    let responses_to_target = match &target_user {
+        Some(target_user) => {
+            let history = data.get::<CommandHistory>().unwrap();
+            messages
+                .iter()
+                .filter(|msg| msg.author.id == target_user.id)
+                .filter_map(|msg| history.get(&msg.id).copied())
+                .collect()
+        }
+        None => HashSet::new(),
+    };
+
+    let to_delete = messages
         .iter()
         .filter(|msg| {
             if msg.author.id != bot_id {
                 return false;
             }
+            if let Some(target_user) = &target_user {
+                let mentions_target = msg.mentions.iter().any(|user| user.id == target_user.id);
+                if !mentions_target && !responses_to_target.contains(&msg.id) {
+                    return false;
+                }
+            }
             if is_mod {
                 return true;
             }
@@ -35,6 +160,51 @@ pub fn cleanup(args: &Args, mod_role_id: RoleId) -> Result<(), Error> {
             true
         })
         .take(num_messages)
+        .collect::<Vec<_>>();
+
+    if dry_run {
+        let message_link = |msg: &Message| {
+            format!(
+                "https://discord.com/channels/{}/{}/{}",
+                match args.msg.guild_id {
+                    Some(guild_id) => guild_id.to_string(),
+                    None => "@me".to_owned(),
+                },
+                msg.channel_id,
+                msg.id
+            )
+        };
+        // The messages are ordered from newest to oldest
+        let reply = match (to_delete.last(), to_delete.first()) {
+            (Some(oldest), Some(newest)) => format!(
+                "Would delete {} messages, from {} to {}",
+                to_delete.len(),
+                message_link(oldest),
+                message_link(newest)
+            ),
+            _ => "Would delete no messages".to_owned(),
+        };
+        // Don't hold the data lock, `send_reply` needs it
+        drop(data);
+        return crate::api::send_reply(args, &reply);
+    }
+
+    // Discord's bulk delete endpoint only accepts 2 to 100 messages that are younger than 14 days,
+    // and only works in servers. Everything else is deleted one by one
+    let (bulk_deletable, single_deletable): (Vec<_>, Vec<_>) =
+        to_delete.into_iter().partition(|msg| {
+            args.msg.guild_id.is_some() && (args.msg.timestamp - msg.timestamp).num_days() < 14
+        });
+    let single_deletable = if bulk_deletable.len() >= 2 {
+        args.msg
+            .channel_id
+            .delete_messages(&args.cx.http, bulk_deletable.iter().map(|msg| msg.id))?;
+        single_deletable
+    } else {
+        bulk_deletable.into_iter().chain(single_deletable).collect()
+    };
+    single_deletable
+        .iter()
         .try_for_each(|msg| msg.delete(&args.cx.http))?;
 
     crate::react_custom_emoji(args, "rustOk", '👌')
@@ -43,14 +213,74 @@ pub fn cleanup(args: &Args, mod_role_id: RoleId) -> Result<(), Error> {
 pub fn cleanup_help(args: &Args) -> Result<(), Error> {
     crate::api::send_reply(
         args,
-        "?cleanup [limit]
+        "?cleanup [limit] [@member | member ID | member=name] [dryrun]
 
 Deletes the bot's messages for cleanup.
 You can specify how many messages to look for. Only messages from the last 24 hours can be deleted,
-except for mods",
+except for mods.
+If a member is given, only the bot's responses to that member's commands and messages mentioning \
+them are deleted.
+With dryrun, nothing is deleted, the bot only tells how many messages would be deleted",
     )
 }
 
+/// Case-insensitive substring check
+///
+/// ```rust
+/// assert!(contains_ignore_case("Ferris", "ferris"));
+/// assert!(contains_ignore_case("Ferris the Crab", "CRAB"));
+/// assert!(!contains_ignore_case("Ferris", "crab"));
+/// ```
+fn contains_ignore_case(haystack: &str, needle: &str) -> bool {
+    haystack.to_lowercase().contains(&needle.to_lowercase())
+}
+
+/// Levenshtein distance between two strings, i.e. the number of single character insertions,
+/// deletions or substitutions needed to turn one into the other
+///
+/// ```rust
+/// assert_eq!(levenshtein("ferris", "ferris"), 0);
+/// assert_eq!(levenshtein("feris", "ferris"), 1);
+/// assert_eq!(levenshtein("kitten", "sitting"), 3);
+/// assert!(levenshtein("ferris", "a completely different name") > 2);
+/// ```
+fn levenshtein(a: &str, b: &str) -> usize {
+    let b = b.chars().collect::<Vec<_>>();
+
+    // distances[j] is the distance between the current prefix of a and the first j chars of b
+    let mut distances = (0..=b.len()).collect::<Vec<_>>();
+    for (i, a_char) in a.chars().enumerate() {
+        let mut previous_diagonal = distances[0];
+        distances[0] = i + 1;
+        for (j, &b_char) in b.iter().enumerate() {
+            let substitution_cost = if a_char == b_char { 0 } else { 1 };
+            let distance = (previous_diagonal + substitution_cost)
+                .min(distances[j] + 1)
+                .min(distances[j + 1] + 1);
+            previous_diagonal = distances[j + 1];
+            distances[j + 1] = distance;
+        }
+    }
+    distances[b.len()]
+}
+
+enum MemberLookup<'a> {
+    Found(&'a Member),
+    /// All members that match equally well, if there's more than one
+    Ambiguous(Vec<&'a Member>),
+    NotFound,
+}
+
+impl<'a> MemberLookup<'a> {
+    fn from_matches(mut matches: Vec<&'a Member>) -> Self {
+        match matches.len() {
+            0 => MemberLookup::NotFound,
+            1 => MemberLookup::Found(matches.remove(0)),
+            _ => MemberLookup::Ambiguous(matches),
+        }
+    }
+}
+
 /// Look up a guild member by a string, case-insensitively.
 ///
 /// The lookup strategy is as follows (in order):
@@ -59,7 +289,47 @@ except for mods",
 /// 3. Lookup by name#discrim
 /// 4. Lookup by name
 /// 5. Lookup by nickname
-fn parse_member<'a>(members: &'a HashMap<UserId, Member>, string: &str) -> Option<&'a Member> {
+/// 6. Lookup by name or nickname containing the string
+/// 7. Lookup by name or nickname with the smallest edit distance, if it's at most 2 (for typos)
+///
+/// ID and mention are always unambiguous. For the other strategies, multiple members can match, in
+/// which case all of them are returned, so the caller can ask which one was meant
+///
+/// ```rust
+/// let member = |id: u64, name: &str, nick: &str| -> Member {
+///     serde_json::from_value(serde_json::json!({
+///         "deaf": false,
+///         "guild_id": 1,
+///         "joined_at": null,
+///         "mute": false,
+///         "nick": nick,
+///         "roles": [],
+///         "user": { "id": id, "avatar": null, "discriminator": "0001", "username": name },
+///     }))
+///     .unwrap()
+/// };
+/// let members = vec![
+///     member(1, "ferris", "crab"),
+///     member(2, "corro", "crab"),
+///     member(3, "clippy", "paperclip"),
+/// ]
+/// .into_iter()
+/// .map(|member| {
+///     let id = member.user.read().id;
+///     (id, member)
+/// })
+/// .collect::<HashMap<_, _>>();
+///
+/// match parse_member(&members, "crab") {
+///     MemberLookup::Ambiguous(candidates) => assert_eq!(candidates.len(), 2),
+///     _ => panic!("two members share the nickname"),
+/// }
+/// match parse_member(&members, "paperclip") {
+///     MemberLookup::Found(member) => assert_eq!(member.user.read().name, "clippy"),
+///     _ => panic!("the nickname is unique"),
+/// }
+/// ```
+fn parse_member<'a>(members: &'a HashMap<UserId, Member>, string: &str) -> MemberLookup<'a> {
     let lookup_by_id = || members.get(&UserId(string.parse().ok()?));
 
     let lookup_by_mention = || {
@@ -74,33 +344,219 @@ fn parse_member<'a>(members: &'a HashMap<UserId, Member>, string: &str) -> Optio
     };
 
     let lookup_by_name_and_discrim = || {
-        let pound_sign = string.find('#')?;
-        let name = &string[..pound_sign];
-        let discrim = string[(pound_sign + 1)..].parse::<u16>().ok()?;
-        members.values().find(|member| {
-            let member = member.user.read();
-            member.discriminator == discrim && member.name.eq_ignore_ascii_case(name)
-        })
+        let name_and_discrim = || {
+            let pound_sign = string.find('#')?;
+            let name = &string[..pound_sign];
+            let discrim = string[(pound_sign + 1)..].parse::<u16>().ok()?;
+            Some((name, discrim))
+        };
+        let (name, discrim) = match name_and_discrim() {
+            Some(x) => x,
+            None => return Vec::new(),
+        };
+        members
+            .values()
+            .filter(|member| {
+                let member = member.user.read();
+                member.discriminator == discrim && member.name.eq_ignore_ascii_case(name)
+            })
+            .collect::<Vec<_>>()
     };
 
     let lookup_by_name = || {
         members
             .values()
-            .find(|member| member.user.read().name == string)
+            .filter(|member| member.user.read().name.eq_ignore_ascii_case(string))
+            .collect::<Vec<_>>()
     };
 
     let lookup_by_nickname = || {
-        members.values().find(|member| match &member.nick {
-            Some(nick) => nick.eq_ignore_ascii_case(string),
-            None => false,
-        })
+        members
+            .values()
+            .filter(|member| match &member.nick {
+                Some(nick) => nick.eq_ignore_ascii_case(string),
+                None => false,
+            })
+            .collect::<Vec<_>>()
+    };
+
+    let lookup_by_substring = || {
+        // an empty string would be contained in every name
+        if string.is_empty() {
+            return Vec::new();
+        }
+        members
+            .values()
+            .filter(|member| {
+                contains_ignore_case(&member.user.read().name, string)
+                    || match &member.nick {
+                        Some(nick) => contains_ignore_case(nick, string),
+                        None => false,
+                    }
+            })
+            .collect::<Vec<_>>()
+    };
+
+    let lookup_by_edit_distance = || {
+        const MAX_DISTANCE: usize = 2;
+
+        // very short strings are within the max distance of any short name
+        if string.chars().count() <= MAX_DISTANCE {
+            return Vec::new();
+        }
+        let string = string.to_lowercase();
+        let distance = |member: &Member| {
+            let name_distance = levenshtein(&member.user.read().name.to_lowercase(), &string);
+            match &member.nick {
+                Some(nick) => name_distance.min(levenshtein(&nick.to_lowercase(), &string)),
+                None => name_distance,
+            }
+        };
+
+        let distances = members
+            .values()
+            .map(|member| (member, distance(member)))
+            .collect::<Vec<_>>();
+        let min_distance = distances.iter().map(|&(_, d)| d).min();
+        match min_distance {
+            Some(min_distance) if min_distance <= MAX_DISTANCE => distances
+                .into_iter()
+                .filter(|&(_, d)| d == min_distance)
+                .map(|(member, _)| member)
+                .collect(),
+            _ => Vec::new(),
+        }
+    };
+
+    if let Some(member) = lookup_by_id().or_else(lookup_by_mention) {
+        return MemberLookup::Found(member);
+    }
+
+    let mut matches = lookup_by_name_and_discrim();
+    if matches.is_empty() {
+        matches = lookup_by_name();
+    }
+    if matches.is_empty() {
+        matches = lookup_by_nickname();
+    }
+    if matches.is_empty() {
+        matches = lookup_by_substring();
+    }
+    if matches.is_empty() {
+        matches = lookup_by_edit_distance();
+    }
+    MemberLookup::from_matches(matches)
+}
+
+/// Find the member of the given guild that the string refers to, see [`parse_member`]. If there is
+/// no such member, the message gets a ❌ reaction, and if it's ambiguous, the candidates are listed
+/// in a reply. In both cases, None is returned
+fn find_member_user(args: &Args, guild_id: GuildId, string: &str) -> Result<Option<User>, Error> {
+    let guild = match guild_id.to_guild_cached(&args.cx.cache) {
+        Some(guild) => guild,
+        None => {
+            args.msg.react(&args.cx.http, ReactionType::from('❌'))?;
+            return Ok(None);
+        }
     };
+    let guild = guild.read();
+
+    match parse_member(&guild.members, string) {
+        MemberLookup::Found(member) => Ok(Some(member.user.read().clone())),
+        MemberLookup::Ambiguous(candidates) => {
+            const MAX_LISTED_CANDIDATES: usize = 10;
 
-    lookup_by_id()
-        .or_else(lookup_by_mention)
-        .or_else(lookup_by_name_and_discrim)
-        .or_else(lookup_by_name)
-        .or_else(lookup_by_nickname)
+            let mut reply = format!(
+                "Found {} people matching `{}`, please be more specific:",
+                candidates.len(),
+                string
+            );
+            for candidate in candidates.iter().take(MAX_LISTED_CANDIDATES) {
+                let user = candidate.user.read();
+                reply += &format!("\n- {}#{}", user.name, user.discriminator);
+            }
+            if candidates.len() > MAX_LISTED_CANDIDATES {
+                reply += "\n...";
+            }
+            drop(guild);
+
+            crate::api::send_reply(args, &reply)?;
+            Ok(None)
+        }
+        MemberLookup::NotFound => {
+            args.msg.react(&args.cx.http, ReactionType::from('❌'))?;
+            Ok(None)
+        }
+    }
+}
+
+/// How often each user has been joke-banned, per guild. The counts are saved to a JSON file after
+/// every change, so the leaderboard survives restarts
+pub struct JokeBanBoard {
+    path: String,
+    /// guild ID -> user ID -> number of bans
+    counts: HashMap<u64, HashMap<u64, u64>>,
+}
+
+impl TypeMapKey for JokeBanBoard {
+    type Value = JokeBanBoard;
+}
+
+impl JokeBanBoard {
+    /// Load the leaderboard from the given file, or start with an empty one if the file doesn't
+    /// exist yet
+    pub fn load(path: String) -> Result<Self, Error> {
+        let counts = match std::fs::read_to_string(&path) {
+            Ok(json) => serde_json::from_str(&json)?,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => HashMap::new(),
+            Err(e) => return Err(e.into()),
+        };
+        Ok(Self { path, counts })
+    }
+
+    fn increment(&mut self, guild_id: GuildId, user_id: UserId) -> Result<(), Error> {
+        *self
+            .counts
+            .entry(guild_id.0)
+            .or_default()
+            .entry(user_id.0)
+            .or_default() += 1;
+        self.save()
+    }
+
+    fn decrement(&mut self, guild_id: GuildId, user_id: UserId) -> Result<(), Error> {
+        if let Some(guild_counts) = self.counts.get_mut(&guild_id.0) {
+            if let Some(count) = guild_counts.get_mut(&user_id.0) {
+                *count = count.saturating_sub(1);
+                if *count == 0 {
+                    guild_counts.remove(&user_id.0);
+                }
+            }
+        }
+        self.save()
+    }
+
+    fn save(&self) -> Result<(), Error> {
+        std::fs::write(&self.path, serde_json::to_string(&self.counts)?)?;
+        Ok(())
+    }
+}
+
+/// Split the arguments of `?ban` and `?kick` into the member and the reason. Only the first word is the
+/// member, so mentions of other people in the reason don't get in the way
+///
+/// ```rust
+/// assert_eq!(split_ban_args("ferris"), ("ferris", None));
+/// assert_eq!(split_ban_args(" ferris  for  crabbing "), ("ferris", Some("for  crabbing")));
+/// assert_eq!(split_ban_args("<@123>\nfor pinging <@456>"), ("<@123>", Some("for pinging <@456>")));
+/// assert_eq!(split_ban_args(""), ("", None));
+/// ```
+fn split_ban_args(body: &str) -> (&str, Option<&str>) {
+    let body = body.trim();
+    match body.find(char::is_whitespace) {
+        Some(member_end) => (&body[..member_end], Some(body[member_end..].trim())),
+        None => (body, None),
+    }
 }
 
 pub fn joke_ban(args: &Args) -> Result<(), Error> {
@@ -109,40 +565,331 @@ pub fn joke_ban(args: &Args) -> Result<(), Error> {
         None => return crate::api::send_reply(args, "🤨"),
     };
 
-    let mut parts = args.body.splitn(2, ' ');
-    let banned_person = parts.next().unwrap();
-    let reason = parts.next();
+    let (banned_person, reason) = split_ban_args(args.body);
+    if banned_person.is_empty() {
+        return joke_ban_help(args);
+    }
 
-    // Convert banned_person string to serenity Member
-    let banned_person = guild_id.to_guild_cached(&args.cx.cache).and_then(|guild| {
-        parse_member(&guild.read().members, banned_person).map(|m| m.user.read().clone())
-    });
+    let banned_person = match find_member_user(args, guild_id, banned_person)? {
+        Some(x) => x,
+        None => return Ok(()),
+    };
 
-    match banned_person {
-        Some(banned_person) => crate::api::send_reply(
+    if banned_person.id == args.msg.author.id {
+        return crate::api::send_reply(
             args,
             &format!(
-                "{}#{} banned user {}#{}{}  {}",
+                "{}#{} tried to ban themselves. Self-care is important, so they're \
+                pardoned  {}",
                 args.msg.author.name,
                 args.msg.author.discriminator,
-                banned_person.name,
-                banned_person.discriminator,
-                match reason {
-                    Some(reason) => format!(" {}", reason.trim()),
-                    None => String::new(),
-                },
                 crate::custom_emoji_code(args, "ferrisBanne", '🔨')
             ),
-        ),
-        None => Ok(args.msg.react(&args.cx.http, ReactionType::from('❌'))?),
+        );
+    }
+
+    let mut data = args.cx.data.write();
+    let ban_board = data.get_mut::<JokeBanBoard>().unwrap();
+    if let Err(e) = ban_board.increment(guild_id, banned_person.id) {
+        warn!("Couldn't save the ban leaderboard: {}", e);
     }
+    drop(data);
+
+    info!(
+        "{}#{} joke-banned {}#{}",
+        args.msg.author.name,
+        args.msg.author.discriminator,
+        banned_person.name,
+        banned_person.discriminator
+    );
+
+    // With ping=false, the name is written out instead of mentioning the user
+    let banned_person = match args.params.get("ping") {
+        Some(&"false") => format!("{}#{}", banned_person.name, banned_person.discriminator),
+        _ => banned_person.mention(),
+    };
+
+    crate::api::send_reply(
+        args,
+        &format!(
+            "{}#{} banned user {}{}  {}",
+            args.msg.author.name,
+            args.msg.author.discriminator,
+            banned_person,
+            match reason {
+                Some(reason) => format!(" {}", reason),
+                None => String::new(),
+            },
+            crate::custom_emoji_code(args, "ferrisBanne", '🔨')
+        ),
+    )
 }
 
 pub fn joke_ban_help(args: &Args) -> Result<(), Error> {
     crate::api::send_reply(
         args,
-        "?ban <member> [reason]
+        "?ban [ping=false] <member> [reason]
+
+Bans another person. With ping=false, the banned person isn't pinged",
+    )
+}
+
+pub fn kick(args: &Args, mod_role_id: RoleId) -> Result<(), Error> {
+    let guild_id = match args.msg.guild_id {
+        Some(x) => x,
+        None => return crate::api::send_reply(args, "Kicking only works in a server"),
+    };
+
+    if !is_mod(args, mod_role_id) {
+        return crate::api::send_reply(args, "Only mods can kick people");
+    }
+
+    let (kicked_person, reason) = split_ban_args(args.body);
+    if kicked_person.is_empty() {
+        return kick_help(args);
+    }
+
+    let kicked_person = match find_member_user(args, guild_id, kicked_person)? {
+        Some(x) => x,
+        None => return Ok(()),
+    };
+
+    match reason {
+        Some(reason) => guild_id.kick_with_reason(&args.cx.http, kicked_person.id, reason)?,
+        None => guild_id.kick(&args.cx.http, kicked_person.id)?,
+    }
+
+    crate::api::send_reply(
+        args,
+        &format!(
+            "Kicked {}#{}",
+            kicked_person.name, kicked_person.discriminator
+        ),
+    )?;
+    crate::react_custom_emoji(args, "rustOk", '👌')
+}
+
+pub fn kick_help(args: &Args) -> Result<(), Error> {
+    crate::api::send_reply(
+        args,
+        "?kick <member> [reason]
+
+Kicks a member from the server. Only usable by mods",
+    )
+}
+
+/// Parse a duration like `30s`, `10m`, `1h` or `7d`
+///
+/// ```rust
+/// assert_eq!(parse_duration("10m"), Some(chrono::Duration::minutes(10)));
+/// assert_eq!(parse_duration("1h"), Some(chrono::Duration::hours(1)));
+/// assert_eq!(parse_duration("10"), None);
+/// assert_eq!(parse_duration("m"), None);
+/// assert_eq!(parse_duration("0s"), Some(chrono::Duration::zero()));
+/// ```
+fn parse_duration(string: &str) -> Option<chrono::Duration> {
+    let unit_start = string.find(|c: char| !c.is_ascii_digit())?;
+    // parsed as u32, because chrono panics on durations that are too large
+    let amount = string[..unit_start].parse::<u32>().ok()?.into();
+    match &string[unit_start..] {
+        "s" => Some(chrono::Duration::seconds(amount)),
+        "m" => Some(chrono::Duration::minutes(amount)),
+        "h" => Some(chrono::Duration::hours(amount)),
+        "d" => Some(chrono::Duration::days(amount)),
+        _ => None,
+    }
+}
+
+const TIMEOUT_USAGE: &str = "Usage: ?timeout <member> <duration> [reason]";
+
+/// Percent-encode everything but unreserved URL characters, which is how Discord expects the
+/// audit log reason header, because header values can't contain arbitrary Unicode
+///
+/// ```rust
+/// assert_eq!(percent_encode("spam"), "spam");
+/// assert_eq!(percent_encode("50% spam, 🦀"), "50%25%20spam%2C%20%F0%9F%A6%80");
+/// ```
+fn percent_encode(string: &str) -> String {
+    let mut encoded = String::new();
+    for byte in string.bytes() {
+        match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' => {
+                encoded.push(byte as char)
+            }
+            _ => encoded += &format!("%{:02X}", byte),
+        }
+    }
+    encoded
+}
+
+pub fn timeout(args: &Args, mod_role_id: RoleId) -> Result<(), Error> {
+    let guild_id = match args.msg.guild_id {
+        Some(x) => x,
+        None => return crate::api::send_reply(args, "Timeouts only work in a server"),
+    };
+
+    if !is_mod(args, mod_role_id) {
+        return crate::api::send_reply(args, "Only mods can time out people");
+    }
+
+    let (member, duration, reason) = match split_ban_args(args.body) {
+        (member, Some(rest)) => {
+            let (duration, reason) = split_ban_args(rest);
+            (member, duration, reason)
+        }
+        _ => return crate::api::send_reply(args, TIMEOUT_USAGE),
+    };
+    let duration = match parse_duration(duration) {
+        Some(x) => x,
+        None => {
+            return crate::api::send_reply(
+                args,
+                &format!(
+                    "Invalid duration `{}`, use for example `30s`, `10m`, `1h` or `7d`",
+                    duration
+                ),
+            )
+        }
+    };
+    // A timeout that ends right away would do nothing
+    if duration.is_zero() {
+        return crate::api::send_reply(args, "Timeouts must be longer than zero");
+    }
+    // Discord's limits for timeouts and audit log reasons
+    if duration > chrono::Duration::days(28) {
+        return crate::api::send_reply(args, "Timeouts can be at most 28 days long");
+    }
+    if reason.is_some_and(|reason| reason.chars().count() > 512) {
+        return crate::api::send_reply(args, "The reason can be at most 512 characters long");
+    }
+
+    let member = match find_member_user(args, guild_id, member)? {
+        Some(x) => x,
+        None => return Ok(()),
+    };
+
+    // serenity doesn't support timeouts yet, so the Discord API is called directly
+    let mut request = args
+        .http
+        .patch(&format!(
+            "https://discord.com/api/v10/guilds/{}/members/{}",
+            guild_id, member.id
+        ))
+        .header(reqwest::header::AUTHORIZATION, &args.cx.http.token)
+        .json(&serde_json::json!({
+            "communication_disabled_until": (chrono::Utc::now() + duration).to_rfc3339(),
+        }));
+    if let Some(reason) = reason {
+        request = request.header("X-Audit-Log-Reason", percent_encode(reason));
+    }
+    request.send()?.error_for_status()?;
+
+    crate::react_custom_emoji(args, "rustOk", '👌')
+}
+
+pub fn timeout_help(args: &Args) -> Result<(), Error> {
+    crate::api::send_reply(
+        args,
+        "?timeout <member> <duration> [reason]
+
+Times out a member, so they can't send messages or join voice channels. The duration is given \
+like `30s`, `10m`, `1h` or `7d`, up to 28 days. The reason is shown in the audit log. Only \
+usable by mods",
+    )
+}
+
+pub fn joke_unban(args: &Args) -> Result<(), Error> {
+    let guild_id = match args.msg.guild_id {
+        Some(x) => x,
+        None => return crate::api::send_reply(args, "🤨"),
+    };
+
+    let unbanned_person = match find_member_user(args, guild_id, args.body.trim())? {
+        Some(x) => x,
+        None => return Ok(()),
+    };
+
+    let mut data = args.cx.data.write();
+    let ban_board = data.get_mut::<JokeBanBoard>().unwrap();
+    if let Err(e) = ban_board.decrement(guild_id, unbanned_person.id) {
+        warn!("Couldn't save the ban leaderboard: {}", e);
+    }
+    drop(data);
+
+    crate::api::send_reply(
+        args,
+        &format!(
+            "{}#{} unbanned user {}#{}  {}",
+            args.msg.author.name,
+            args.msg.author.discriminator,
+            unbanned_person.name,
+            unbanned_person.discriminator,
+            crate::custom_emoji_code(args, "ferrisHappy", '🕊')
+        ),
+    )
+}
+
+pub fn joke_unban_help(args: &Args) -> Result<(), Error> {
+    crate::api::send_reply(
+        args,
+        "?unban <member>
+
+Unbans another person",
+    )
+}
+
+pub fn ban_board(args: &Args) -> Result<(), Error> {
+    const LEADERBOARD_SIZE: usize = 10;
+
+    let guild_id = match args.msg.guild_id {
+        Some(x) => x,
+        None => return crate::api::send_reply(args, "🤨"),
+    };
+
+    let mut counts = {
+        let data = args.cx.data.read();
+        match data.get::<JokeBanBoard>().unwrap().counts.get(&guild_id.0) {
+            Some(counts) => counts
+                .iter()
+                .map(|(&user_id, &count)| (UserId(user_id), count))
+                .collect::<Vec<_>>(),
+            None => Vec::new(),
+        }
+    };
+    if counts.is_empty() {
+        return crate::api::send_reply(args, "Nobody has been banned yet");
+    }
+    counts.sort_by(|(_, a), (_, b)| b.cmp(a));
+
+    let guild = guild_id.to_guild_cached(&args.cx.cache);
+    let mut reply = "Most banned people:\n".to_owned();
+    for (place, (user_id, count)) in counts.iter().take(LEADERBOARD_SIZE).enumerate() {
+        // not mentioning the users, to not ping them
+        let name = guild
+            .as_ref()
+            .and_then(|guild| {
+                let guild = guild.read();
+                let user = guild.members.get(user_id)?.user.read();
+                Some(format!("{}#{}", user.name, user.discriminator))
+            })
+            .unwrap_or_else(|| format!("unknown user {}", user_id));
+        reply += &format!(
+            "{}. {} ({} {})\n",
+            place + 1,
+            name,
+            count,
+            if *count == 1 { "ban" } else { "bans" }
+        );
+    }
+
+    crate::api::send_reply(args, &reply)
+}
+
+pub fn ban_board_help(args: &Args) -> Result<(), Error> {
+    crate::api::send_reply(
+        args,
+        "?banboard
 
-Bans another person",
+Shows who has been banned the most on this server",
     )
 }
@@ -1,16 +1,34 @@
 //! run rust code on the rust-lang playground
 
-use crate::{api, commands::Args, Error};
+use crate::{
+    api,
+    commands::{Args, UsageStats},
+    Error,
+};
 
+use indexmap::IndexMap;
 use reqwest::header;
-use serde::{Deserialize, Serialize};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use serenity::model::id::{ChannelId, UserId};
+use serenity::prelude::TypeMapKey;
+use std::borrow::Cow;
+use std::collections::HashMap;
 use std::str::FromStr;
-use std::{borrow::Cow, collections::HashMap};
+use std::time::{Duration, Instant};
 
 // ================================
 // PLAYGROUND API WRAPPER BEGINS HERE
 // ================================
 
+/// How long to wait for the playground before giving up. Compiling and running small snippets
+/// usually takes a few seconds
+const PLAYGROUND_TIMEOUT: Duration = Duration::from_secs(30);
+/// Benchmarks alone run for up to 15 seconds, on top of compiling in release mode
+const BENCH_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// The playground has no option for JSON diagnostics (`--message-format=json`), it always sends
+/// rustc's human-readable output. So diagnostics are parsed from that text where needed, see
+/// [`Diagnostic`] and [`apply_suggestions`]
 #[derive(Debug, Serialize)]
 struct PlaygroundRequest<'a> {
     channel: Channel,
@@ -20,16 +38,44 @@ struct PlaygroundRequest<'a> {
     crate_type: CrateType,
     mode: Mode,
     tests: bool,
+    /// Run with RUST_BACKTRACE=1
+    backtrace: bool,
 }
 
 #[derive(Debug, Serialize)]
 struct MiriRequest<'a> {
     edition: Edition,
+    #[serde(rename = "crateType")]
+    crate_type: CrateType,
+    code: &'a str,
+    #[serde(rename = "aliasingModel")]
+    aliasing_model: AliasingModel,
+}
+
+#[derive(Debug, Serialize)]
+struct CompileRequest<'a> {
+    target: Target,
+    #[serde(rename = "assemblyFlavor")]
+    assembly_flavor: &'static str,
+    #[serde(rename = "demangleAssembly")]
+    demangle_assembly: &'static str,
+    /// "filter" strips directives and unused labels from the assembly
+    #[serde(rename = "processAssembly")]
+    process_assembly: &'static str,
+    channel: Channel,
+    mode: Mode,
+    edition: Edition,
+    #[serde(rename = "crateType")]
+    crate_type: CrateType,
+    tests: bool,
     code: &'a str,
 }
 
-// has the same fields
-type MacroExpansionRequest<'a> = MiriRequest<'a>;
+#[derive(Debug, Serialize)]
+struct MacroExpansionRequest<'a> {
+    edition: Edition,
+    code: &'a str,
+}
 
 #[derive(Debug, Serialize)]
 struct ClippyRequest<'a> {
@@ -60,12 +106,45 @@ impl FromStr for Channel {
     }
 }
 
-#[derive(Debug, Clone, Copy, Serialize)]
+/// Pick one of the channels for `channel=random`, based on the seed
+///
+/// ```rust
+/// assert_eq!(random_channel(42).to_string(), random_channel(42).to_string());
+/// let picked = (0..30).map(|seed| random_channel(seed).to_string()).collect::<Vec<_>>();
+/// for channel in &["stable", "beta", "nightly"] {
+///     assert!(picked.iter().any(|picked| picked == channel));
+/// }
+/// ```
+fn random_channel(seed: u64) -> Channel {
+    // one round of splitmix64, so that similar seeds still give different channels
+    let mut x = seed.wrapping_add(0x9E37_79B9_7F4A_7C15);
+    x = (x ^ (x >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+    x = (x ^ (x >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+    x ^= x >> 31;
+    [Channel::Stable, Channel::Beta, Channel::Nightly][(x % 3) as usize]
+}
+
+impl std::fmt::Display for Channel {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            Channel::Stable => "stable",
+            Channel::Beta => "beta",
+            Channel::Nightly => "nightly",
+        })
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize)]
 enum Edition {
     #[serde(rename = "2015")]
     E2015,
     #[serde(rename = "2018")]
     E2018,
+    #[serde(rename = "2021")]
+    E2021,
+    /// Only available on the nightly channel
+    #[serde(rename = "2024")]
+    E2024,
 }
 
 impl FromStr for Edition {
@@ -75,11 +154,71 @@ impl FromStr for Edition {
         match s {
             "2015" => Ok(Edition::E2015),
             "2018" => Ok(Edition::E2018),
+            "2021" => Ok(Edition::E2021),
+            "2024" => Ok(Edition::E2024),
             _ => Err(format!("invalid edition `{}`", s).into()),
         }
     }
 }
 
+impl std::fmt::Display for Edition {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            Edition::E2015 => "2015",
+            Edition::E2018 => "2018",
+            Edition::E2021 => "2021",
+            Edition::E2024 => "2024",
+        })
+    }
+}
+
+/// Output format of the playground's compile endpoint
+#[derive(Debug, Clone, Copy, Serialize)]
+#[serde(rename_all = "kebab-case")]
+enum Target {
+    Asm,
+    LlvmIr,
+    Mir,
+    Hir,
+    Wasm,
+}
+
+impl FromStr for Target {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Error> {
+        match s {
+            "asm" => Ok(Target::Asm),
+            "llvm-ir" => Ok(Target::LlvmIr),
+            "mir" => Ok(Target::Mir),
+            "hir" => Ok(Target::Hir),
+            "wasm" => Ok(Target::Wasm),
+            _ => Err(format!("invalid target `{}`", s).into()),
+        }
+    }
+}
+
+impl Target {
+    /// Language to use for the Discord code block highlighting
+    fn code_block_lang(self) -> &'static str {
+        match self {
+            Target::Asm => "x86asm",
+            Target::LlvmIr => "llvm",
+            Target::Mir => "rust",
+            Target::Hir => "rust",
+            Target::Wasm => "wasm",
+        }
+    }
+}
+
+/// The aliasing rules Miri checks pointer accesses against
+#[derive(Debug, Clone, Copy, Serialize)]
+#[serde(rename_all = "snake_case")]
+enum AliasingModel {
+    Stacked,
+    Tree,
+}
+
 #[derive(Debug, Clone, Copy, Serialize)]
 enum CrateType {
     #[serde(rename = "bin")]
@@ -88,6 +227,18 @@ enum CrateType {
     Library,
 }
 
+impl FromStr for CrateType {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Error> {
+        match s {
+            "bin" => Ok(CrateType::Binary),
+            "lib" => Ok(CrateType::Library),
+            _ => Err(format!("invalid crate type `{}`", s).into()),
+        }
+    }
+}
+
 #[derive(Debug, Clone, Copy, Serialize)]
 #[serde(rename_all = "snake_case")]
 enum Mode {
@@ -107,6 +258,66 @@ impl FromStr for Mode {
     }
 }
 
+/// The playground only compiles with opt-level 0 (debug mode) and 3 (release mode), the other
+/// levels are only honored by Compiler Explorer
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum OptLevel {
+    O0,
+    O1,
+    O2,
+    O3,
+    Size,
+    MinSize,
+}
+
+impl FromStr for OptLevel {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Error> {
+        match s {
+            "0" => Ok(OptLevel::O0),
+            "1" => Ok(OptLevel::O1),
+            "2" => Ok(OptLevel::O2),
+            "3" => Ok(OptLevel::O3),
+            "s" => Ok(OptLevel::Size),
+            "z" => Ok(OptLevel::MinSize),
+            _ => Err(format!("invalid opt_level `{}`, expected 0, 1, 2, 3, s or z", s).into()),
+        }
+    }
+}
+
+/// The playground always compiles with `panic=unwind`, only Compiler Explorer honors `abort`
+#[derive(Debug, Clone, Copy)]
+enum PanicStrategy {
+    Unwind,
+    Abort,
+}
+
+impl FromStr for PanicStrategy {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Error> {
+        match s {
+            "unwind" => Ok(PanicStrategy::Unwind),
+            "abort" => Ok(PanicStrategy::Abort),
+            _ => Err(format!("invalid panic strategy `{}`, expected unwind or abort", s).into()),
+        }
+    }
+}
+
+impl std::fmt::Display for OptLevel {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            OptLevel::O0 => "0",
+            OptLevel::O1 => "1",
+            OptLevel::O2 => "2",
+            OptLevel::O3 => "3",
+            OptLevel::Size => "s",
+            OptLevel::MinSize => "z",
+        })
+    }
+}
+
 #[derive(Debug, Deserialize)]
 struct PlayResult {
     success: bool,
@@ -114,25 +325,166 @@ struct PlayResult {
     stderr: String,
 }
 
+#[derive(Debug, Deserialize)]
+struct CompileResult {
+    success: bool,
+    /// The compiler output in the requested target format
+    code: String,
+    stderr: String,
+}
+
+#[derive(Debug, Serialize)]
+struct GistRequest<'a> {
+    code: &'a str,
+}
+
+/// The playground's response when creating or fetching a gist. Other fields, like the GitHub URL,
+/// are ignored
+///
+/// ```rust
+/// let response = r#"{
+///     "id": "9c8d7f4e6b1a2c3d5e7f8a9b0c1d2e3f",
+///     "url": "https://gist.github.com/rust-play/9c8d7f4e6b1a2c3d5e7f8a9b0c1d2e3f",
+///     "code": "fn main() {}"
+/// }"#;
+/// let gist: GistResponse = parse_playground_response(200, response).unwrap();
+/// assert_eq!(gist.id, "9c8d7f4e6b1a2c3d5e7f8a9b0c1d2e3f");
+/// assert_eq!(gist.code, "fn main() {}");
+///
+/// let response = r#"{"error":"GitHub API error"}"#;
+/// let error = parse_playground_response::<GistResponse>(500, response).unwrap_err();
+/// assert_eq!(
+///     error.to_string(),
+///     r#"The playground sent an unexpected response (500): `{"error":"GitHub API error"}`"#,
+/// );
+/// ```
+#[derive(Debug, Deserialize)]
+struct GistResponse {
+    id: String,
+    code: String,
+}
+
+/// Replace reqwest's timeout error with something users can make sense of
+fn timeout_error(e: reqwest::Error) -> Error {
+    if e.is_timeout() {
+        "The playground took too long to respond, please try again later".into()
+    } else {
+        e.into()
+    }
+}
+
+fn count_playground_request(args: &Args) {
+    let mut data = args.cx.data.write();
+    data.get_mut::<UsageStats>().unwrap().playground_requests += 1;
+}
+
 /// Returns a gist ID
 fn post_gist(args: &Args, code: &str) -> Result<String, Error> {
-    let mut payload = HashMap::new();
-    payload.insert("code", code);
-
+    count_playground_request(args);
     let resp = args
         .http
         .post("https://play.rust-lang.org/meta/gist/")
+        .timeout(PLAYGROUND_TIMEOUT)
+        .header(header::REFERER, "https://discord.gg/rust-lang")
+        .json(&GistRequest { code })
+        .send()
+        .map_err(timeout_error)?;
+
+    let status = resp.status();
+    let gist: GistResponse = parse_playground_response(status, &resp.text()?)?;
+    info!("gist response: {:?}", gist);
+
+    Ok(gist.id)
+}
+
+/// Fetch the code of a gist that was created via the playground
+fn fetch_gist(args: &Args, gist_id: &str) -> Result<String, Error> {
+    count_playground_request(args);
+    let gist: GistResponse = args
+        .http
+        .get(&format!("https://play.rust-lang.org/meta/gist/{}", gist_id))
+        .timeout(PLAYGROUND_TIMEOUT)
         .header(header::REFERER, "https://discord.gg/rust-lang")
-        .json(&payload)
+        .send()
+        .map_err(timeout_error)?
+        .error_for_status()
+        .map_err(|e| format!("couldn't fetch gist `{}`: {}", gist_id, e))?
+        .json()?;
+    Ok(gist.code)
+}
+
+#[derive(Debug, Deserialize)]
+struct GithubGist {
+    files: HashMap<String, GithubGistFile>,
+}
+
+#[derive(Debug, Deserialize)]
+struct GithubGistFile {
+    size: u64,
+    /// Missing for files that are too large for the API response
+    content: Option<String>,
+}
+
+/// Extract the gist ID from the path of a `gist.github.com` URL, which is either `/<user>/<id>` or
+/// just `/<id>`
+///
+/// ```rust
+/// assert_eq!(github_gist_id("/kangalioo/0abc9f"), Some("0abc9f"));
+/// assert_eq!(github_gist_id("/0abc9f/"), Some("0abc9f"));
+/// assert_eq!(github_gist_id("/kangalioo"), None);
+/// assert_eq!(github_gist_id("/kangalioo/0abc9f/revisions"), None);
+/// ```
+fn github_gist_id(path: &str) -> Option<&str> {
+    let segments = path
+        .split('/')
+        .filter(|s| !s.is_empty())
+        .collect::<Vec<_>>();
+    match segments.as_slice() {
+        [id] | [_, id] if id.chars().all(|c| c.is_ascii_hexdigit()) => Some(id),
+        _ => None,
+    }
+}
+
+/// Fetch the code of a GitHub gist. If the gist has several files, the first `.rs` file is used
+fn fetch_github_gist(args: &Args, gist_id: &str) -> Result<String, Error> {
+    let response = args
+        .http
+        .get(&format!("https://api.github.com/gists/{}", gist_id))
+        .timeout(PLAYGROUND_TIMEOUT)
+        .header(header::USER_AGENT, crate::crates::USER_AGENT)
         .send()?;
+    if response.status() == reqwest::StatusCode::NOT_FOUND {
+        return Err(format!("Couldn't find the gist `{}`, maybe it was deleted", gist_id).into());
+    }
+    let gist: GithubGist = response
+        .error_for_status()
+        .map_err(|e| format!("couldn't fetch gist `{}`: {}", gist_id, e))?
+        .json()?;
 
-    let mut resp: HashMap<String, String> = resp.json()?;
-    info!("gist response: {:?}", resp);
+    let mut files = gist.files.into_iter().collect::<Vec<_>>();
+    files.sort_by(|a, b| a.0.cmp(&b.0));
+    let (file_name, file) = match files.iter().position(|(name, _)| name.ends_with(".rs")) {
+        Some(i) => files.swap_remove(i),
+        None if files.len() == 1 => files.remove(0),
+        None => return Err(format!("The gist `{}` has no .rs file", gist_id).into()),
+    };
 
-    let gist_id = resp.remove("id").ok_or("no gist found")?;
-    Ok(gist_id)
+    // Checked here instead of with `check_code_size`, because the API leaves out the content of
+    // large files
+    if file.size > crate::MAX_CODE_SIZE as u64 {
+        return Err(format!(
+            "`{}` is too large, the limit is {} KB",
+            file_name,
+            crate::MAX_CODE_SIZE / 1024
+        )
+        .into());
+    }
+    Ok(file
+        .content
+        .ok_or_else(|| format!("couldn't fetch the content of `{}`", file_name))?)
 }
 
+// Playground links can't carry the opt_level and panic flags, the playground UI has no such options
 fn url_from_gist(flags: &CommandFlags, gist_id: &str) -> String {
     format!(
         "https://play.rust-lang.org/?version={}&mode={}&edition={}&gist={}",
@@ -148,15 +500,212 @@ fn url_from_gist(flags: &CommandFlags, gist_id: &str) -> String {
         match flags.edition {
             Edition::E2015 => "2015",
             Edition::E2018 => "2018",
+            Edition::E2021 => "2021",
+            Edition::E2024 => "2024",
         },
         gist_id
     )
 }
 
+/// Send a POST request with the given JSON body to the playground. Connection errors, timeouts
+/// and 502/503/504 responses are retried up to three times with exponential backoff, since the
+/// playground is down for short periods every now and then
+fn post_with_retry(
+    request: reqwest::blocking::RequestBuilder,
+    body: &impl Serialize,
+) -> Result<reqwest::blocking::Response, Error> {
+    const MAX_RETRIES: u32 = 3;
+
+    for attempt in 0..=MAX_RETRIES {
+        if attempt > 0 {
+            std::thread::sleep(Duration::from_millis(500 * 2u64.pow(attempt - 1)));
+        }
+
+        let request = request.try_clone().ok_or("request can't be retried")?;
+        match request.json(body).send() {
+            Ok(response) => match response.status().as_u16() {
+                // bad gateway, service unavailable, gateway timeout
+                502..=504 => warn!("playground returned {}, retrying", response.status()),
+                _ => return Ok(response),
+            },
+            // Builder errors are our fault, and retrying after a timeout would keep the user
+            // waiting for too long. Everything else is a connection error
+            Err(e) if e.is_builder() || e.is_timeout() => return Err(timeout_error(e)),
+            Err(e) => warn!("couldn't reach the playground, retrying: {}", e),
+        }
+    }
+
+    Err("The playground is having trouble right now, please try again later".into())
+}
+
+/// Recently received playground responses, keyed on the endpoint URL and the request body, so
+/// every command has its own namespace. The entries are ordered from least to most recently used
+pub struct PlaygroundCache;
+
+impl TypeMapKey for PlaygroundCache {
+    type Value = IndexMap<String, (Instant, String)>;
+}
+
+/// How long `?last` can send an output again
+const LAST_OUTPUT_TTL: Duration = Duration::from_secs(30 * 60);
+
+/// The most recent successful output for each user and channel, and when it was sent, for `?last`
+pub struct LastOutputs;
+
+impl TypeMapKey for LastOutputs {
+    type Value = HashMap<(UserId, ChannelId), (Instant, LastOutput)>;
+}
+
+/// A reply split like the arguments of [`crate::reply_paginated_long_text`], so that it's
+/// truncated the same way when it's sent again
+#[derive(Clone)]
+pub struct LastOutput {
+    text_body: String,
+    text_end: String,
+    truncation_msg: String,
+}
+
+/// Parse a playground response body. During outages the playground serves HTML error pages instead
+/// of JSON, so a parse error mentions the HTTP status and the start of the body rather than
+/// serde's message
+///
+/// ```rust
+/// let html = "<html>\n<head><title>502 Bad Gateway</title></head>\n<body>nginx</body>\n</html>";
+/// let error = parse_playground_response::<PlayResult>("502 Bad Gateway", html).unwrap_err();
+/// assert_eq!(
+///     error.to_string(),
+///     "The playground sent an unexpected response (502 Bad Gateway): \
+///     `<html> <head><title>502 Bad Gateway</title></head> <body>nginx</body> </html>`",
+/// );
+///
+/// let error = parse_playground_response::<PlayResult>("500", &"a".repeat(1000)).unwrap_err();
+/// assert!(error.to_string().ends_with("aaa...`"));
+/// ```
+fn parse_playground_response<T: DeserializeOwned>(
+    status: impl std::fmt::Display,
+    body: &str,
+) -> Result<T, Error> {
+    const MAX_SNIPPET_LENGTH: usize = 100;
+
+    serde_json::from_str(body).map_err(|e| {
+        warn!("couldn't parse playground response: {}\n{}", e, body);
+
+        // inline code can't span multiple lines
+        let body = body.split_whitespace().collect::<Vec<_>>().join(" ");
+        let snippet = match body.char_indices().nth(MAX_SNIPPET_LENGTH) {
+            Some((end, _)) => format!("{}...", &body[..end]),
+            None => body,
+        };
+        format!(
+            "The playground sent an unexpected response ({}): `{}`",
+            status, snippet
+        )
+        .into()
+    })
+}
+
+/// Return the cached response for `key` if there is one, otherwise call `send` and cache the
+/// response. Error responses aren't cached, and reruns through a 🔁 reaction skip the cache, as
+/// does `timing=true`, because a cached result would report a bogus duration
+fn cached_request<T: DeserializeOwned>(
+    args: &Args,
+    flags: &CommandFlags,
+    key: String,
+    send: impl FnOnce() -> Result<reqwest::blocking::Response, Error>,
+) -> Result<T, Error> {
+    const CACHE_SIZE: usize = 64;
+    const CACHE_TTL: Duration = Duration::from_secs(5 * 60);
+
+    if !(flags.no_cache || flags.timing || args.is_rerun) {
+        let mut data = args.cx.data.write();
+        let cache = data.get_mut::<PlaygroundCache>().unwrap();
+        // Re-insert the entry to mark it as most recently used
+        if let Some((created, response)) = cache.shift_remove(&key) {
+            if created.elapsed() < CACHE_TTL {
+                let result = serde_json::from_str(&response)?;
+                cache.insert(key, (created, response));
+                return Ok(result);
+            }
+        }
+    }
+
+    count_playground_request(args);
+    let response = send()?;
+    let status = response.status();
+    let response = response.text()?;
+    let result = parse_playground_response(status, &response)?;
+    if !status.is_success() {
+        return Ok(result);
+    }
+
+    let mut data = args.cx.data.write();
+    let cache = data.get_mut::<PlaygroundCache>().unwrap();
+    cache.insert(key, (Instant::now(), response));
+    if cache.len() > CACHE_SIZE {
+        cache.drain(..cache.len() - CACHE_SIZE);
+    }
+
+    Ok(result)
+}
+
+/// Like [`post_with_retry`], but successful responses are cached for a few minutes, because the
+/// same snippet is often run several times during a discussion. `nocache=true` bypasses the cache
+fn cached_post<T: DeserializeOwned>(
+    args: &Args,
+    flags: &CommandFlags,
+    url: &str,
+    body: &impl Serialize,
+) -> Result<T, Error> {
+    let key = format!("{} {}", url, serde_json::to_string(body)?);
+    cached_request(args, flags, key, || {
+        post_with_retry(args.http.post(url).timeout(PLAYGROUND_TIMEOUT), body)
+    })
+}
+
 // ================================
 // UTILITY FUNCTIONS BEGIN HERE
 // ================================
 
+/// Like [`crate::extract_code`], but additionally accepts a playground link
+/// (`https://play.rust-lang.org/?gist=...`) or a GitHub gist link
+/// (`https://gist.github.com/user/...`), in which case the code is fetched from the gist
+fn extract_code_or_gist<'a>(args: &Args<'a>) -> Result<Cow<'a, str>, Error> {
+    // people often put links into <> to suppress the embed
+    let body = args
+        .body
+        .trim()
+        .trim_start_matches('<')
+        .trim_end_matches('>');
+    let is_github_gist = body.contains("gist.github.com");
+    if !(body.contains("play.rust-lang.org") || is_github_gist)
+        || body.contains(char::is_whitespace)
+    {
+        return crate::extract_code(args.body);
+    }
+
+    let url = if body.starts_with("http://") || body.starts_with("https://") {
+        reqwest::Url::parse(body)
+    } else {
+        reqwest::Url::parse(&format!("https://{}", body))
+    }
+    .map_err(|e| format!("invalid gist link: {}", e))?;
+
+    if is_github_gist {
+        let gist_id = github_gist_id(url.path()).ok_or("invalid gist link: no gist ID found")?;
+        return Ok(Cow::Owned(fetch_github_gist(args, gist_id)?));
+    }
+
+    let gist_id = url
+        .query_pairs()
+        .find(|(key, _)| key == "gist")
+        .map(|(_, gist_id)| gist_id.into_owned())
+        .ok_or("invalid playground link: no gist ID found")?;
+
+    let code = fetch_gist(args, &gist_id)?;
+    crate::check_code_size(&code)?;
+    Ok(Cow::Owned(code))
+}
+
 // Small thing about multiline strings: while hacking on this file I was unsure how to handle
 // trailing newlines in multiline strings:
 // - should they have one ("hello\nworld\n")
@@ -170,70 +719,365 @@ fn url_from_gist(flags: &CommandFlags, gist_id: &str) -> String {
 
 struct CommandFlags {
     channel: Channel,
+    /// Whether the channel was picked at random with `channel=random`, so the reply names it
+    random_channel: bool,
     mode: Mode,
+    /// If set, overrides the mode. Levels other than 0 and 3 are compiled in release mode on the
+    /// playground
+    opt_level: Option<OptLevel>,
+    panic: PanicStrategy,
     edition: Edition,
+    /// If set, the code is compiled to this target and the output shown instead of running it
+    target: Option<Target>,
+    /// If not set, the crate type is inferred from whether the code has a main function
+    crate_type: Option<CrateType>,
+    backtrace: bool,
+    /// How many seconds microbench measures for
+    bench_time: u64,
+    /// Whether to show how long the playground took to compile and run the code
+    timing: bool,
+    /// Whether to skip the cache of recent playground responses
+    no_cache: bool,
+    /// If not set, the code is only compiled
+    run: bool,
+    /// Whether clippy should also show the code with its suggestions applied
+    fix: bool,
+    /// Whether eval prints the result with `{:#?}` instead of `{:?}`
+    pretty: bool,
+    /// Whether the reply always has a compiler and an output section, instead of the layout
+    /// depending on whether the code ran successfully
+    sections: bool,
+    /// Whether to also show the code that was sent to the playground, after wrapping it
+    show_source: bool,
+    /// Whether compiler diagnostics are shortened to one line with the offending code each
+    compact_diagnostics: bool,
 }
 
-/// Returns the parsed flags and a String of parse errors. The parse error string will have a
-/// trailing newline (except if empty)
-fn parse_flags(args: &Args) -> (CommandFlags, String) {
+fn parse_bool(flag_name: &str, s: &str) -> Result<bool, Error> {
+    match s {
+        "true" => Ok(true),
+        "false" => Ok(false),
+        _ => Err(format!(
+            "invalid {} value `{}`, expected true or false",
+            flag_name, s
+        )
+        .into()),
+    }
+}
+
+/// Names of the flags that [`parse_flags`] understands, which can also be given inline
+const FLAG_NAMES: &[&str] = &[
+    "channel",
+    "version",
+    "mode",
+    "opt_level",
+    "panic",
+    "edition",
+    "target",
+    "crate_type",
+    "backtrace",
+    "timing",
+    "nocache",
+    "run",
+    "fix",
+    "pretty",
+    "sections",
+    "showsource",
+    "diagnostics",
+    "bench_time",
+];
+
+/// Collect the flags from the message parameters and from `// key: value` comments in the leading
+/// lines of the code, so that shared snippets can carry their own flags. Comments whose key isn't
+/// a flag name are ordinary comments and ignored. Message parameters take precedence
+///
+/// ```rust
+/// let code = "// channel: beta\n// edition:2018\n// note: the fn is empty\n// mode:\n\
+///     fn main() {}\n// run: false";
+/// let message_params = [("edition", "2015")].iter().copied().collect();
+/// let (params, errors) = flag_params(&message_params, code);
+/// assert_eq!(params.len(), 2);
+/// assert_eq!(params["channel"], "beta");
+/// assert_eq!(params["edition"], "2015");
+/// assert_eq!(errors, "missing value for inline flag `mode`\n");
+/// ```
+fn flag_params<'a>(
+    message_params: &HashMap<&'a str, &'a str>,
+    code: &'a str,
+) -> (HashMap<&'a str, &'a str>, String) {
+    let mut params = HashMap::new();
     let mut errors = String::new();
+    for line in code.lines().map(str::trim) {
+        if line.is_empty() {
+            continue;
+        }
+        // doc comments belong to the code
+        if !line.starts_with("//") || line.starts_with("///") || line.starts_with("//!") {
+            break;
+        }
+
+        let mut parts = line[2..].splitn(2, ':');
+        let (key, value) = match (parts.next(), parts.next()) {
+            (Some(key), Some(value)) => (key.trim(), value.trim()),
+            _ => continue,
+        };
+        if !FLAG_NAMES.contains(&key) {
+            continue;
+        }
+        if value.is_empty() {
+            errors += &format!("missing value for inline flag `{}`\n", key);
+            continue;
+        }
+        params.insert(key, value);
+    }
+
+    params.extend(message_params.iter().map(|(&key, &value)| (key, value)));
+    (params, errors)
+}
+
+/// Returns the parsed flags and a String of parse errors. The parse error string will have a
+/// trailing newline (except if empty). Besides the message parameters, flags can be given in
+/// comments at the start of `code`, the user's code before it's wrapped, see [`flag_params`]
+fn parse_flags(args: &Args, code: &str) -> (CommandFlags, String) {
+    let (params, mut errors) = flag_params(&args.params, code);
 
     let mut flags = CommandFlags {
         channel: Channel::Nightly,
+        random_channel: false,
         mode: Mode::Debug,
-        edition: Edition::E2018,
+        opt_level: None,
+        panic: PanicStrategy::Unwind,
+        edition: Edition::E2021,
+        target: None,
+        crate_type: None,
+        backtrace: false,
+        bench_time: 5,
+        timing: false,
+        no_cache: false,
+        run: true,
+        fix: false,
+        pretty: false,
+        sections: false,
+        show_source: false,
+        compact_diagnostics: false,
     };
 
-    if let Some(channel) = args.params.get("channel") {
-        match channel.parse() {
-            Ok(c) => flags.channel = c,
-            Err(e) => errors += &format!("{}\n", e),
+    if let Some(channel) = params.get("channel") {
+        if *channel == "random" {
+            let nanos = std::time::SystemTime::now()
+                .duration_since(std::time::UNIX_EPOCH)
+                .map_or(0, |time| time.subsec_nanos());
+            flags.channel = random_channel(args.msg.id.0 ^ u64::from(nanos));
+            flags.random_channel = true;
+        } else {
+            match channel.parse() {
+                Ok(c) => flags.channel = c,
+                Err(e) => errors += &format!("{}\n", e),
+            }
+        }
+    }
+
+    // The playground only provides the latest toolchain of each channel, so specific versions like
+    // `nightly-2020-10-01` or `1.47.0` can't be pinned. Naming a channel works like channel=
+    if let Some(version) = params.get("version") {
+        match version.parse() {
+            Ok(c) => {
+                flags.channel = c;
+                flags.random_channel = false;
+            }
+            Err(_) => {
+                errors += &format!(
+                    "version `{}` can't be pinned, the playground only provides the latest \
+                    stable, beta and nightly\n",
+                    version
+                )
+            }
         }
     }
 
-    if let Some(mode) = args.params.get("mode") {
+    if let Some(mode) = params.get("mode") {
         match mode.parse() {
             Ok(m) => flags.mode = m,
             Err(e) => errors += &format!("{}\n", e),
         }
     }
 
-    if let Some(edition) = args.params.get("edition") {
+    if let Some(opt_level) = params.get("opt_level") {
+        match opt_level.parse() {
+            Ok(o) => {
+                flags.opt_level = Some(o);
+                flags.mode = match o {
+                    OptLevel::O0 => Mode::Debug,
+                    _ => Mode::Release,
+                };
+            }
+            Err(e) => errors += &format!("{}\n", e),
+        }
+    }
+
+    if let Some(panic) = params.get("panic") {
+        match panic.parse() {
+            Ok(p) => flags.panic = p,
+            Err(e) => errors += &format!("{}\n", e),
+        }
+    }
+
+    if let Some(edition) = params.get("edition") {
         match edition.parse() {
             Ok(e) => flags.edition = e,
             Err(e) => errors += &format!("{}\n", e),
         }
     }
 
-    (flags, errors)
-}
+    if let Some(target) = params.get("target") {
+        match target.parse() {
+            Ok(t) => flags.target = Some(t),
+            Err(e) => errors += &format!("{}\n", e),
+        }
+    }
 
-fn generic_help(
-    args: &Args,
-    cmd: &str,
-    desc: &str,
-    full: bool,
-    example_code: &str,
-) -> Result<(), Error> {
-    let mut reply = format!(
-        "{}. All code is executed on https://play.rust-lang.org.\n",
-        desc
-    );
+    if let Some(crate_type) = params.get("crate_type") {
+        match crate_type.parse() {
+            Ok(c) => flags.crate_type = Some(c),
+            Err(e) => errors += &format!("{}\n", e),
+        }
+    }
 
-    reply += &format!(
-        "```?{} {}edition={{}} ``\u{200B}`{}``\u{200B}` ```\n",
-        cmd,
-        if full { "mode={} channel={} " } else { "" },
-        example_code,
-    );
+    if let Some(backtrace) = params.get("backtrace") {
+        match parse_bool("backtrace", backtrace) {
+            Ok(b) => flags.backtrace = b,
+            Err(e) => errors += &format!("{}\n", e),
+        }
+    }
 
-    reply += "Optional arguments:\n";
-    if full {
-        reply += "    \tmode: debug, release (default: debug)\n";
-        reply += "    \tchannel: stable, beta, nightly (default: nightly)\n";
+    if let Some(timing) = params.get("timing") {
+        match parse_bool("timing", timing) {
+            Ok(t) => flags.timing = t,
+            Err(e) => errors += &format!("{}\n", e),
+        }
     }
-    reply += "    \tedition: 2015, 2018 (default: 2018)\n";
+
+    if let Some(no_cache) = params.get("nocache") {
+        match parse_bool("nocache", no_cache) {
+            Ok(b) => flags.no_cache = b,
+            Err(e) => errors += &format!("{}\n", e),
+        }
+    }
+
+    if let Some(run) = params.get("run") {
+        match parse_bool("run", run) {
+            Ok(r) => flags.run = r,
+            Err(e) => errors += &format!("{}\n", e),
+        }
+    }
+
+    if let Some(fix) = params.get("fix") {
+        match parse_bool("fix", fix) {
+            Ok(f) => flags.fix = f,
+            Err(e) => errors += &format!("{}\n", e),
+        }
+    }
+
+    if let Some(pretty) = params.get("pretty") {
+        match parse_bool("pretty", pretty) {
+            Ok(p) => flags.pretty = p,
+            Err(e) => errors += &format!("{}\n", e),
+        }
+    }
+
+    if let Some(sections) = params.get("sections") {
+        match parse_bool("sections", sections) {
+            Ok(s) => flags.sections = s,
+            Err(e) => errors += &format!("{}\n", e),
+        }
+    }
+
+    if let Some(show_source) = params.get("showsource") {
+        match parse_bool("showsource", show_source) {
+            Ok(s) => flags.show_source = s,
+            Err(e) => errors += &format!("{}\n", e),
+        }
+    }
+
+    if let Some(diagnostics) = params.get("diagnostics") {
+        match *diagnostics {
+            "full" => flags.compact_diagnostics = false,
+            "compact" => flags.compact_diagnostics = true,
+            _ => {
+                errors += &format!(
+                    "invalid diagnostics value `{}`, expected full or compact\n",
+                    diagnostics
+                )
+            }
+        }
+    }
+
+    if let Some(bench_time) = params.get("bench_time") {
+        match bench_time.parse() {
+            // longer runs would hit the playground timeout
+            Ok(t @ 1..=15) => flags.bench_time = t,
+            Ok(t) => errors += &format!("bench_time must be between 1 and 15 seconds, got {}\n", t),
+            Err(_) => errors += &format!("invalid bench_time `{}`\n", bench_time),
+        }
+    }
+
+    if let (Edition::E2024, Channel::Stable) | (Edition::E2024, Channel::Beta) =
+        (flags.edition, flags.channel)
+    {
+        errors += "edition 2024 is only available on the nightly channel\n";
+        flags.edition = Edition::E2021;
+    }
+
+    (flags, errors)
+}
+
+fn generic_help(
+    args: &Args,
+    cmd: &str,
+    desc: &str,
+    full: bool,
+    example_code: &str,
+) -> Result<(), Error> {
+    let mut reply = format!(
+        "{}. All code is executed on https://play.rust-lang.org.\n",
+        desc
+    );
+
+    reply += &format!(
+        "```?{} {}edition={{}} ``\u{200B}`{}``\u{200B}` ```\n",
+        cmd,
+        if full {
+            "mode={} channel={} target={} "
+        } else {
+            ""
+        },
+        example_code,
+    );
+
+    reply += "Optional arguments:\n";
+    if full {
+        reply += "    \tmode: debug, release (default: debug)\n";
+        reply += "    \topt_level: 0, 1, 2, 3, s, z (default: from mode, the playground only has 0 and 3)\n";
+        reply += "    \tchannel: stable, beta, nightly, random (default: nightly)\n";
+        reply +=
+            "    \tversion: same as channel, specific versions can't be pinned on the playground\n";
+        reply += "    \tpanic: unwind, abort (default: unwind, the playground only has unwind)\n";
+        reply += "    \ttarget: asm, llvm-ir, mir, hir, wasm (default: run the code)\n";
+        reply += "    \tcrate_type: bin, lib (default: bin if there's a main function)\n";
+        reply += "    \tbacktrace: true, false (default: false)\n";
+        reply += "    \ttiming: true, false (default: false, true always runs the code again \
+            instead of using a cached result)\n";
+        reply += "    \trun: true, false (default: true, false only compiles the code)\n";
+        reply += "    \tsections: true, false (default: false, true always shows the compiler \
+            and the program output separately)\n";
+        reply += "    \tshowsource: true, false (default: false, true also shows the code with \
+            the `fn main` and other code that the bot generated around it)\n";
+        reply += "    \tdiagnostics: full, compact (default: full, compact shows each error \
+            and warning as one line with the code it points to)\n";
+    }
+    reply += "    \tedition: 2015, 2018, 2021, 2024 (default: 2021, 2024 is nightly-only)\n";
+    reply += "    \tnocache: true, false (default: false)\n";
+    reply += "The flags can also be given as `// flag: value` comments at the start of the code\n";
 
     api::send_reply(args, &reply)
 }
@@ -244,6 +1088,27 @@ fn generic_help(
 ///
 /// If multiple potential tokens could be used as a stripping point, this function will make the
 /// stripped output as compact as possible and choose from the matching tokens accordingly.
+///
+/// The lines containing the tokens are always removed entirely. Everything before the end token
+/// line is kept, no matter on which line the end token is:
+///
+/// ```rust
+/// let end = &["error: aborting"];
+/// assert_eq!(extract_relevant_lines("error: aborting due to 2 errors", &[], end), "");
+/// assert_eq!(extract_relevant_lines("a\nerror: aborting\nb\n", &[], end), "a\n");
+/// assert_eq!(extract_relevant_lines("a\nb\nerror: aborting", &[], end), "a\nb\n");
+///
+/// // the end token closest to the start wins
+/// let end = &["error: aborting", "warning emitted"];
+/// let stderr = "a\nwarning: 1 warning emitted\nb\nerror: aborting\n";
+/// assert_eq!(extract_relevant_lines(stderr, &[], end), "a\n");
+///
+/// let stderr = "Compiling playground\nwarning: unused\n\n1 warning emitted";
+/// assert_eq!(
+///     extract_relevant_lines(stderr, &["Compiling playground"], &["warning emitted"]),
+///     "warning: unused\n",
+/// );
+/// ```
 // Note to self: don't use "Finished dev" as a parameter to this, because that will break in release
 // compilation mode
 fn extract_relevant_lines<'a>(
@@ -293,27 +1158,650 @@ enum ResultHandling {
     Discard,
     /// Print the result with `println!("{:?}")`
     Print,
+    /// Print the result with `println!("{:#?}")`, which spreads larger values over multiple lines
+    PrettyPrint,
+    /// Wrap in a `#[test]` function instead of `fn main`, to be run by the test harness
+    Test,
+}
+
+/// Check whether the code already has a main function. This includes `async fn main` with a
+/// runtime attribute like `#[tokio::main]` or `#[async_std::main]`
+///
+/// ```rust
+/// assert!(has_main_fn("fn main() {}"));
+/// assert!(has_main_fn("#[tokio::main]\nasync fn main() {}"));
+/// assert!(has_main_fn("async fn main () {}"));
+/// assert!(!has_main_fn("fn main_loop() {}"));
+/// assert!(!has_main_fn("1 + 2"));
+/// ```
+fn has_main_fn(code: &str) -> bool {
+    if code.contains("#[tokio::main]") || code.contains("#[async_std::main]") {
+        return true;
+    }
+
+    code.match_indices("fn main")
+        .any(|(i, fn_main)| code[(i + fn_main.len())..].trim_start().starts_with('('))
+}
+
+/// Check whether the code starts with a `#![no_std]` attribute, among its other crate attributes
+///
+/// ```rust
+/// assert!(is_no_std("#![no_std]\nfn f() {}"));
+/// assert!(is_no_std("// no allocations\n#![allow(unused)]\n\n#![no_std]"));
+/// assert!(!is_no_std("fn f() {}\n// #![no_std]"));
+/// assert!(!is_no_std("#![no_implicit_prelude]"));
+/// ```
+fn is_no_std(code: &str) -> bool {
+    code.lines()
+        .map(str::trim)
+        .take_while(|line| line.is_empty() || line.starts_with("//") || line.starts_with("#!["))
+        .any(|line| line.replace(' ', "") == "#![no_std]")
+}
+
+/// Take the environment variables out of `// env: KEY=VALUE` comment lines, because the playground
+/// can't set any. The comment lines are emptied rather than removed, so the line numbers stay the
+/// same. Malformed lines are reported in `errors`
+///
+/// ```rust
+/// let mut errors = String::new();
+/// let code = "// env: RUST_LOG=debug\n//env:GREETING=hello world\nlet x = 1;";
+/// assert_eq!(
+///     extract_env_vars(code, &mut errors),
+///     ("\n\nlet x = 1;".to_owned(), vec![("RUST_LOG", "debug"), ("GREETING", "hello world")]),
+/// );
+/// assert_eq!(errors, "");
+///
+/// let (_, env_vars) = extract_env_vars("// env: NO_VALUE\n// env: =x", &mut errors);
+/// assert_eq!(env_vars, []);
+/// assert_eq!(errors.lines().count(), 2);
+/// ```
+fn extract_env_vars<'a>(code: &'a str, errors: &mut String) -> (String, Vec<(&'a str, &'a str)>) {
+    let mut env_vars = Vec::new();
+    let lines = code
+        .lines()
+        .map(|line| {
+            let env_var = match line.trim().strip_prefix("//") {
+                Some(comment) => match comment.trim_start().strip_prefix("env:") {
+                    Some(env_var) => env_var.trim(),
+                    None => return line,
+                },
+                None => return line,
+            };
+            match env_var.split_once('=') {
+                Some((key, value)) if !key.trim().is_empty() => {
+                    env_vars.push((key.trim(), value.trim()))
+                }
+                _ => {
+                    *errors += &format!(
+                        "invalid env comment `{}`, expected `// env: KEY=VALUE`\n",
+                        line.trim()
+                    )
+                }
+            }
+            ""
+        })
+        .collect::<Vec<_>>();
+    (lines.join("\n"), env_vars)
+}
+
+/// Set the environment variables at the start of the main function, on the line of the opening
+/// brace so the line numbers don't change. Since edition 2024, `std::env::set_var` is unsafe.
+/// Returns `None` if there's no main function
+///
+/// ```rust
+/// assert_eq!(
+///     inject_env_vars("fn main() {\n}", &[("KEY", "a \"b\"")], Edition::E2021).as_deref(),
+///     Some("fn main() { std::env::set_var(\"KEY\", \"a \\\"b\\\"\");\n}"),
+/// );
+/// assert_eq!(
+///     inject_env_vars("fn main() {}", &[("A", "1")], Edition::E2024).as_deref(),
+///     Some("fn main() { unsafe { std::env::set_var(\"A\", \"1\") }}"),
+/// );
+/// assert_eq!(inject_env_vars("pub fn f() {}", &[("A", "1")], Edition::E2021), None);
+/// assert_eq!(inject_env_vars("pub fn f() {}", &[], Edition::E2021).as_deref(), Some("pub fn f() {}"));
+/// ```
+fn inject_env_vars(code: &str, env_vars: &[(&str, &str)], edition: Edition) -> Option<String> {
+    if env_vars.is_empty() {
+        return Some(code.to_owned());
+    }
+
+    let (main_start, fn_main) = code
+        .match_indices("fn main")
+        .find(|(i, fn_main)| code[(i + fn_main.len())..].trim_start().starts_with('('))?;
+    let body_start =
+        main_start + fn_main.len() + code[(main_start + fn_main.len())..].find('{')? + 1;
+
+    let mut set_vars = String::new();
+    for (key, value) in env_vars {
+        set_vars += &match edition >= Edition::E2024 {
+            true => format!(" unsafe {{ std::env::set_var({:?}, {:?}) }}", key, value),
+            false => format!(" std::env::set_var({:?}, {:?});", key, value),
+        };
+    }
+    Some(format!(
+        "{}{}{}",
+        &code[..body_start],
+        set_vars,
+        &code[body_start..]
+    ))
+}
+
+/// Check whether the code awaits futures, in which case it must be wrapped in an async block that's
+/// driven by an executor
+///
+/// ```rust
+/// assert!(!needs_executor("1 + 2"));
+/// assert!(needs_executor("std::future::ready(1 + 2).await"));
+/// assert!(needs_executor("if true { f().await; }"));
+/// // awaiting inside of items and async blocks doesn't need an executor around the code
+/// assert!(!needs_executor("async fn f() { g().await }"));
+/// assert!(!needs_executor("impl X { async fn f(&self) { g().await } }"));
+/// assert!(!needs_executor("let future = async move { f().await };"));
+/// assert!(!needs_executor("println!(\"x.await\"); // y.await"));
+/// assert!(!needs_executor("/* x.await */ let c = '{'; let d = '\\''; e.await_count()"));
+/// ```
+fn needs_executor(code: &str) -> bool {
+    fn is_keyword(code: &str, keyword: &str) -> bool {
+        code.starts_with(keyword)
+            && !code[keyword.len()..].starts_with(|c: char| c.is_alphanumeric() || c == '_')
+    }
+
+    // For each open brace, whether it starts the body of an item or an async block, which can
+    // contain `.await` without the code around it being async
+    let mut braces: Vec<bool> = Vec::new();
+    let mut next_brace_is_body = false;
+    let mut previous_char = ' ';
+    let mut chars = code.char_indices();
+    while let Some((i, c)) = chars.next() {
+        let rest = &code[i..];
+        let at_word_start = !(previous_char.is_alphanumeric() || previous_char == '_');
+        match c {
+            '{' => braces.push(std::mem::take(&mut next_brace_is_body)),
+            '}' => {
+                braces.pop();
+            }
+            ';' => next_brace_is_body = false,
+            '"' => {
+                // skip over the string literal
+                let mut escaped = false;
+                for (_, c) in &mut chars {
+                    match c {
+                        _ if escaped => escaped = false,
+                        '\\' => escaped = true,
+                        '"' => break,
+                        _ => {}
+                    }
+                }
+            }
+            '/' if rest.starts_with("//") => {
+                // skip over the comment
+                for (_, c) in &mut chars {
+                    if c == '\n' {
+                        break;
+                    }
+                }
+            }
+            '/' if rest.starts_with("/*") => {
+                // skip over the comment, block comments can be nested
+                chars.next();
+                let mut depth = 1;
+                let mut previous_char = ' ';
+                for (_, c) in &mut chars {
+                    // a `/` or `*` that's part of a comment marker doesn't start another one
+                    previous_char = match (previous_char, c) {
+                        ('/', '*') => {
+                            depth += 1;
+                            ' '
+                        }
+                        ('*', '/') => {
+                            depth -= 1;
+                            ' '
+                        }
+                        _ => c,
+                    };
+                    if depth == 0 {
+                        break;
+                    }
+                }
+            }
+            // labels and lifetimes also start with `'`, but aren't closed right after one character
+            '\'' if rest.starts_with("'\\") || rest.chars().nth(2) == Some('\'') => {
+                // skip over the char literal
+                let mut escaped = false;
+                for (_, c) in &mut chars {
+                    match c {
+                        _ if escaped => escaped = false,
+                        '\\' => escaped = true,
+                        '\'' => break,
+                        _ => {}
+                    }
+                }
+            }
+            '.' if is_keyword(&rest[1..], "await") && !braces.contains(&true) => return true,
+            _ if at_word_start
+                && ["fn", "impl", "trait", "mod", "async"]
+                    .iter()
+                    .any(|keyword| is_keyword(rest, keyword)) =>
+            {
+                next_brace_is_body = true
+            }
+            _ => {}
+        }
+        previous_char = c;
+    }
+    false
+}
+
+/// APIs that don't work as expected in the playground's sandbox, which has no network access and
+/// only limited filesystem and process access
+const SANDBOXED_APIS: &[&str] = &[
+    "std::fs",
+    "std::net",
+    "std::process::Command",
+    "File::open",
+    "File::create",
+    "TcpStream",
+    "TcpListener",
+    "UdpSocket",
+    "reqwest::",
+];
+
+/// Find the [`SANDBOXED_APIS`] that the code uses. This is only a heuristic for a hint, the code is
+/// run either way
+///
+/// ```rust
+/// assert_eq!(sandboxed_apis("println!(\"hi\");"), Vec::<&str>::new());
+/// assert_eq!(
+///     sandboxed_apis("let s = std::fs::read_to_string(\"a.txt\")?;"),
+///     ["std::fs"],
+/// );
+/// assert_eq!(
+///     sandboxed_apis("use std::net::TcpStream;\nTcpStream::connect(\"1.1.1.1:80\")?;"),
+///     ["std::net", "TcpStream"],
+/// );
+/// ```
+fn sandboxed_apis(code: &str) -> Vec<&'static str> {
+    SANDBOXED_APIS
+        .iter()
+        .copied()
+        .filter(|api| code.contains(api))
+        .collect()
+}
+
+/// Whether the code has a `loop` that can never be left, because there's no `break`, `return`,
+/// `?` or call that ends the program in it. This is only a heuristic for a hint, since such
+/// programs run until the playground's timeout
+///
+/// ```rust
+/// assert!(has_endless_loop("loop {}"));
+/// assert!(has_endless_loop("loop { println!(\"break\"); }"));
+/// assert!(has_endless_loop("loop { for x in 0..3 { break; } }"));
+/// assert!(!has_endless_loop("loop { break; }"));
+/// assert!(!has_endless_loop("'outer: loop { loop { break 'outer; } }"));
+/// assert!(!has_endless_loop("loop { if done() { return; } }"));
+/// assert!(!has_endless_loop("let x = loop { break 5; };"));
+/// assert!(!has_endless_loop("loop { read()?; }"));
+/// assert!(!has_endless_loop("loop { std::process::exit(0) }"));
+/// assert!(!has_endless_loop("while running() {} // loop {}"));
+/// assert!(!has_endless_loop("while running() {} /* loop {} */"));
+/// assert!(has_endless_loop("loop { /* break; */ }"));
+/// assert!(has_endless_loop("loop { /* /* */ break; */ }"));
+/// assert!(!has_endless_loop("loop { if read() == '}' { break; } }"));
+/// assert!(!has_endless_loop("loop { if read() == '\\'' { break; } }"));
+/// ```
+fn has_endless_loop(code: &str) -> bool {
+    fn is_keyword(code: &str, keyword: &str) -> bool {
+        code.starts_with(keyword)
+            && !code[keyword.len()..].starts_with(|c: char| c.is_alphanumeric() || c == '_')
+    }
+
+    // For each open brace: None if it doesn't start a loop body, else whether the loop can be left.
+    // `for` and `while` loops end by themselves
+    let mut braces: Vec<Option<bool>> = Vec::new();
+    let mut next_brace = None;
+    let mut previous_char = ' ';
+    let mut chars = code.char_indices();
+    while let Some((i, c)) = chars.next() {
+        let rest = &code[i..];
+        let at_word_start = !(previous_char.is_alphanumeric() || previous_char == '_');
+        let leaves_all_loops = at_word_start
+            && [
+                "return",
+                "panic",
+                "unreachable",
+                "todo",
+                "unimplemented",
+                "exit",
+            ]
+            .iter()
+            .any(|keyword| is_keyword(rest, keyword));
+        match c {
+            '{' => braces.push(next_brace.take()),
+            '}' => {
+                if let Some(Some(false)) = braces.pop() {
+                    return true;
+                }
+            }
+            '"' => {
+                // skip over the string literal
+                let mut escaped = false;
+                for (_, c) in &mut chars {
+                    match c {
+                        _ if escaped => escaped = false,
+                        '\\' => escaped = true,
+                        '"' => break,
+                        _ => {}
+                    }
+                }
+            }
+            '/' if rest.starts_with("//") => {
+                // skip over the comment
+                for (_, c) in &mut chars {
+                    if c == '\n' {
+                        break;
+                    }
+                }
+            }
+            '/' if rest.starts_with("/*") => {
+                // skip over the comment, block comments can be nested
+                chars.next();
+                let mut depth = 1;
+                let mut previous_char = ' ';
+                for (_, c) in &mut chars {
+                    // a `/` or `*` that's part of a comment marker doesn't start another one
+                    previous_char = match (previous_char, c) {
+                        ('/', '*') => {
+                            depth += 1;
+                            ' '
+                        }
+                        ('*', '/') => {
+                            depth -= 1;
+                            ' '
+                        }
+                        _ => c,
+                    };
+                    if depth == 0 {
+                        break;
+                    }
+                }
+            }
+            // labels and lifetimes also start with `'`, but aren't closed right after one character
+            '\'' if rest.starts_with("'\\") || rest.chars().nth(2) == Some('\'') => {
+                // skip over the char literal
+                let mut escaped = false;
+                for (_, c) in &mut chars {
+                    match c {
+                        _ if escaped => escaped = false,
+                        '\\' => escaped = true,
+                        '\'' => break,
+                        _ => {}
+                    }
+                }
+            }
+            _ if at_word_start && is_keyword(rest, "loop") => next_brace = Some(false),
+            _ if at_word_start && (is_keyword(rest, "for") || is_keyword(rest, "while")) => {
+                next_brace = Some(true)
+            }
+            // a labeled break may leave any of the enclosing loops
+            _ if at_word_start
+                && is_keyword(rest, "break")
+                && !rest["break".len()..].trim_start().starts_with('\'') =>
+            {
+                if let Some(can_be_left) = braces.iter_mut().rev().find_map(Option::as_mut) {
+                    *can_be_left = true;
+                }
+            }
+            _ if c == '?' || leaves_all_loops || (at_word_start && is_keyword(rest, "break")) => {
+                for can_be_left in braces.iter_mut().flatten() {
+                    *can_be_left = true;
+                }
+            }
+            _ => {}
+        }
+        previous_char = c;
+    }
+    false
+}
+
+/// A minimal executor for wrapped code that uses `.await`. It simply polls the future on the
+/// current thread and parks the thread in between. This doesn't provide a runtime, so futures that
+/// depend on one (like tokio's timers and sockets) still need a `#[tokio::main]` function
+const BLOCK_ON_BOILERPLATE: &str = "\
+struct ThreadWaker(std::thread::Thread);
+impl std::task::Wake for ThreadWaker {
+    fn wake(self: std::sync::Arc<Self>) { self.0.unpark(); }
+}
+fn block_on<F: std::future::Future>(future: F) -> F::Output {
+    let mut future = Box::pin(future);
+    let waker = std::sync::Arc::new(ThreadWaker(std::thread::current())).into();
+    let mut cx = std::task::Context::from_waker(&waker);
+    loop {
+        match std::future::Future::poll(future.as_mut(), &mut cx) {
+            std::task::Poll::Ready(output) => return output,
+            std::task::Poll::Pending => std::thread::park(),
+        }
+    }
+}
+";
+
+/// The crate type given with crate_type=, or else the one inferred from the code
+fn crate_type(flags: &CommandFlags, code: &str) -> CrateType {
+    flags.crate_type.unwrap_or_else(|| {
+        if has_main_fn(code) {
+            CrateType::Binary
+        } else {
+            CrateType::Library
+        }
+    })
 }
 
 /// Utility used by the commands to wrap the given code in a `fn main` if not already wrapped.
 /// To check, whether a wrap was done, check if the return type is Cow::Borrowed vs Cow::Owned
+///
+/// Leading crate attributes and `extern crate` declarations are kept at the top level:
+///
+/// ```rust
+/// let code = "#![allow(unused)]\n#[macro_use] extern crate foo;\nlet x = bar!();\nuse std::fmt;";
+/// assert_eq!(
+///     maybe_wrap(code, ResultHandling::None),
+///     "#![allow(unused)]\n#[macro_use] extern crate foo;\nfn main() {\nlet x = bar!();\nuse std::fmt;\n}",
+/// );
+/// ```
+///
+/// The result can be printed in compact or pretty debug format:
+///
+/// ```rust
+/// assert_eq!(
+///     maybe_wrap("vec![1, 2]", ResultHandling::Print),
+///     "fn main() { println!(\"{:?}\", {\nvec![1, 2]\n}); }",
+/// );
+/// assert_eq!(
+///     maybe_wrap("vec![1, 2]", ResultHandling::PrettyPrint),
+///     "fn main() { println!(\"{:#?}\", {\nvec![1, 2]\n}); }",
+/// );
+/// ```
+///
+/// `#![no_std]` code is never wrapped, because the generated `fn main` and `println!` need std.
+/// Without a main function of its own, it's compiled as a library instead:
+///
+/// ```rust
+/// let code = "#![no_std]\npub fn add(a: u32, b: u32) -> u32 { a + b }";
+/// assert!(matches!(maybe_wrap(code, ResultHandling::Print), Cow::Borrowed(_)));
+/// let code = "#![no_std]\n#![no_main]\n#[no_mangle]\npub extern \"C\" fn main() {}";
+/// assert!(matches!(maybe_wrap(code, ResultHandling::None), Cow::Borrowed(_)));
+/// ```
 fn maybe_wrap(code: &str, result_handling: ResultHandling) -> Cow<'_, str> {
-    if code.contains("fn main") {
-        return Cow::Borrowed(code);
+    wrap_code(code, result_handling).0
+}
+
+/// Maps the lines of the code sent to the playground back to the lines of the user's code, which
+/// differ if the code was wrapped. Line numbers are 1-based, like in compiler diagnostics
+struct LineMap {
+    /// For each line of the sent code, the line of the user's code it came from. None for the
+    /// lines generated by [`wrap_code`]
+    user_lines: Vec<Option<usize>>,
+}
+
+impl LineMap {
+    fn user_line(&self, line: usize) -> Option<usize> {
+        *self.user_lines.get(line.checked_sub(1)?)?
+    }
+
+    /// Account for lines that the bot put in front of the user's code before it was wrapped, like
+    /// the lint attributes of ?clippy
+    fn skip_prepended_lines(&mut self, line_count: usize) {
+        for user_line in &mut self.user_lines {
+            *user_line = user_line
+                .and_then(|line| line.checked_sub(line_count))
+                .filter(|&line| line > 0);
+        }
+    }
+
+    /// Rewrite the line numbers in diagnostics, in `src/main.rs:LINE:COL` locations and in the
+    /// gutter of source lines, to those of the user's code. Locations in code generated by the bot
+    /// are marked as such
+    ///
+    /// ```rust
+    /// let (_, line_map) = wrap_code("let x: u32 = -1;", ResultHandling::None);
+    /// let diagnostics = "error[E0600]: cannot apply unary operator `-` to type `u32`
+    ///  --> src/main.rs:2:14
+    ///   |
+    /// 2 | let x: u32 = -1;
+    ///   |              ^^ cannot apply unary operator `-`";
+    /// assert_eq!(
+    ///     line_map.map_diagnostics(diagnostics),
+    ///     "error[E0600]: cannot apply unary operator `-` to type `u32`
+    ///  --> src/main.rs:1:14
+    ///   |
+    /// 1 | let x: u32 = -1;
+    ///   |              ^^ cannot apply unary operator `-`",
+    /// );
+    ///
+    /// assert_eq!(
+    ///     line_map.map_diagnostics("thread 'main' panicked at src/main.rs:3:1:\n"),
+    ///     "thread 'main' panicked at src/main.rs:3:1 (in code generated by the bot):\n",
+    /// );
+    ///
+    /// // the gutter keeps its width
+    /// let (_, line_map) = wrap_code(&"x;\n".repeat(12), ResultHandling::None);
+    /// assert_eq!(line_map.map_diagnostics("10 | x;"), " 9 | x;");
+    /// ```
+    fn map_diagnostics(&self, diagnostics: &str) -> String {
+        const FILE_NAMES: &[&str] = &["src/main.rs:", "src/lib.rs:"];
+
+        let mut output = String::with_capacity(diagnostics.len());
+        for line in diagnostics.split_inclusive('\n') {
+            let mut rest = line;
+
+            // the gutter of source lines
+            if let Some((number, _, _)) = parse_diagnostic_source_line(line) {
+                if let Some(user_line) = self.user_line(number) {
+                    let number_end = line.find(|c: char| c.is_ascii_digit()).unwrap_or(0)
+                        + number.to_string().len();
+                    output += &format!("{:>width$}", user_line, width = number_end);
+                    rest = &line[number_end..];
+                }
+            }
+
+            while let Some((file_start, file_name)) = FILE_NAMES
+                .iter()
+                .filter_map(|file_name| Some((rest.find(file_name)?, file_name)))
+                .min()
+            {
+                let number_start = file_start + file_name.len();
+                output += &rest[..number_start];
+                rest = &rest[number_start..];
+
+                let number_end = rest
+                    .find(|c: char| !c.is_ascii_digit())
+                    .unwrap_or(rest.len());
+                let user_line = rest[..number_end]
+                    .parse()
+                    .ok()
+                    .and_then(|line| self.user_line(line));
+                match user_line {
+                    Some(user_line) => {
+                        output += &user_line.to_string();
+                        rest = &rest[number_end..];
+                    }
+                    None if number_end > 0 => {
+                        // include the column, if there is one
+                        let column_end = match rest[number_end..].strip_prefix(':') {
+                            Some(column) if column.starts_with(|c: char| c.is_ascii_digit()) => {
+                                let column_length = column
+                                    .find(|c: char| !c.is_ascii_digit())
+                                    .unwrap_or(column.len());
+                                number_end + 1 + column_length
+                            }
+                            _ => number_end,
+                        };
+                        output += &rest[..column_end];
+                        output += " (in code generated by the bot)";
+                        rest = &rest[column_end..];
+                    }
+                    None => {}
+                }
+            }
+            output += rest;
+        }
+        output
+    }
+}
+
+/// Like [`maybe_wrap`], but also returns which line of the wrapped code corresponds to which line
+/// of the given code
+///
+/// ```rust
+/// let (code, line_map) = wrap_code("fn main() {\n    x\n}", ResultHandling::None);
+/// assert!(matches!(code, Cow::Borrowed(_)));
+/// assert_eq!(line_map.user_line(2), Some(2));
+///
+/// // the crate attribute stays in the first line, the blank line after it is dropped
+/// let (code, line_map) = wrap_code("#![allow(unused)]\n\nlet x = 1;\nx", ResultHandling::None);
+/// assert_eq!(code, "#![allow(unused)]\nfn main() {\nlet x = 1;\nx\n}");
+/// assert_eq!(line_map.user_line(1), Some(1));
+/// assert_eq!(line_map.user_line(2), None);
+/// assert_eq!(line_map.user_line(3), Some(3));
+/// assert_eq!(line_map.user_line(4), Some(4));
+/// assert_eq!(line_map.user_line(5), None);
+///
+/// // the executor boilerplate is generated too
+/// let (_, line_map) = wrap_code("f().await", ResultHandling::None);
+/// let boilerplate_lines = BLOCK_ON_BOILERPLATE.lines().count();
+/// assert_eq!(line_map.user_line(boilerplate_lines + 1), None);
+/// assert_eq!(line_map.user_line(boilerplate_lines + 2), Some(1));
+/// ```
+fn wrap_code(code: &str, result_handling: ResultHandling) -> (Cow<'_, str>, LineMap) {
+    let already_wrapped = match result_handling {
+        ResultHandling::Test => code.contains("#[test]"),
+        _ => has_main_fn(code) || is_no_std(code),
+    };
+    if already_wrapped {
+        let user_lines = (1..=code.lines().count()).map(Some).collect();
+        return (Cow::Borrowed(code), LineMap { user_lines });
     }
 
-    let mut lines = code.lines().peekable();
+    let mut lines = code.lines().enumerate().peekable();
 
     let mut output = String::new();
+    let mut user_lines = Vec::new();
 
     // First go through the input lines and extract the crate attributes at the start. Those will
     // be put right at the beginning of the generated code, else they won't work (crate attributes
-    // need to be at the top of the file)
-    while let Some(line) = lines.peek() {
+    // need to be at the top of the file). The same goes for `#[macro_use] extern crate`, whose
+    // macros wouldn't be usable inside fn main otherwise
+    while let Some(&(line_index, line)) = lines.peek() {
         let line = line.trim();
-        if line.starts_with("#![") {
+        let is_extern_crate = line.starts_with("extern crate")
+            || (line.starts_with("#[macro_use]")
+                && (line.contains("extern crate")
+                    || lines.clone().nth(1).is_some_and(|(_, next_line)| {
+                        next_line.trim().starts_with("extern crate")
+                    })));
+        if line.starts_with("#![") || is_extern_crate {
             output.push_str(line);
             output.push('\n');
+            user_lines.push(Some(line_index + 1));
         } else if line.is_empty() {
             // do nothing, maybe more crate attributes are coming
         } else {
@@ -322,296 +1810,2745 @@ fn maybe_wrap(code: &str, result_handling: ResultHandling) -> Cow<'_, str> {
         lines.next(); // Advance the iterator
     }
 
-    // fn main boilerplate
-    output.push_str(match result_handling {
-        ResultHandling::None => "fn main() {\n",
-        ResultHandling::Discard => "fn main() { let _ = {\n",
-        ResultHandling::Print => "fn main() { println!(\"{:?}\", {\n",
+    // fn main boilerplate. Code that awaits futures is put into an async block, which is then run by
+    // the executor from BLOCK_ON_BOILERPLATE
+    let is_async = needs_executor(code);
+    if is_async {
+        output.push_str(BLOCK_ON_BOILERPLATE);
+        user_lines.extend(BLOCK_ON_BOILERPLATE.lines().map(|_| None));
+    }
+    user_lines.push(None);
+    output.push_str(match (&result_handling, is_async) {
+        (ResultHandling::None, false) => "fn main() {\n",
+        (ResultHandling::Discard, false) => "fn main() { let _ = {\n",
+        (ResultHandling::Print, false) => "fn main() { println!(\"{:?}\", {\n",
+        (ResultHandling::PrettyPrint, false) => "fn main() { println!(\"{:#?}\", {\n",
+        (ResultHandling::Test, false) => "#[test] fn test() {\n",
+        (ResultHandling::None, true) => "fn main() { block_on(async {\n",
+        (ResultHandling::Discard, true) => "fn main() { let _ = block_on(async {\n",
+        (ResultHandling::Print, true) => "fn main() { println!(\"{:?}\", block_on(async {\n",
+        (ResultHandling::PrettyPrint, true) => "fn main() { println!(\"{:#?}\", block_on(async {\n",
+        (ResultHandling::Test, true) => "#[test] fn test() { block_on(async {\n",
     });
 
     // Write the rest of the lines that don't contain crate attributes
-    for line in lines {
+    for (line_index, line) in lines {
         output.push_str(line);
         output.push('\n');
+        user_lines.push(Some(line_index + 1));
     }
 
     // fn main boilerplate counterpart
-    output.push_str(match result_handling {
-        ResultHandling::None => "}",
-        ResultHandling::Discard => "}; }",
-        ResultHandling::Print => "}); }",
+    output.push_str(match (result_handling, is_async) {
+        (ResultHandling::None, false) => "}",
+        (ResultHandling::Discard, false) => "}; }",
+        (ResultHandling::Print | ResultHandling::PrettyPrint, false) => "}); }",
+        (ResultHandling::Test, false) => "}",
+        (ResultHandling::None, true) => "}) }",
+        (ResultHandling::Discard, true) => "}); }",
+        (ResultHandling::Print | ResultHandling::PrettyPrint, true) => "})); }",
+        (ResultHandling::Test, true) => "}) }",
     });
+    user_lines.push(None);
 
-    Cow::Owned(output)
+    (Cow::Owned(output), LineMap { user_lines })
 }
 
-/// Send a Discord reply with the formatted contents of a Playground result
-fn send_reply(
-    args: &Args<'_>,
-    result: PlayResult,
-    code: &str,
-    flags: &CommandFlags,
-    flag_parse_errors: &str,
-) -> Result<(), Error> {
-    let result = if !result.success {
-        result.stderr
-    } else if result.stderr.is_empty() {
-        result.stdout
-    } else {
-        format!("{}\n{}", result.stderr, result.stdout)
-    };
+/// Parse the line number of a diagnostic's `--> src/main.rs:LINE:COL` location marker
+///
+/// ```rust
+/// assert_eq!(diagnostic_location_line(" --> src/main.rs:12:5"), Some(12));
+/// assert_eq!(diagnostic_location_line("  --> src/lib.rs:1:1"), Some(1));
+/// assert_eq!(diagnostic_location_line("12 |     let x = 1;"), None);
+/// ```
+fn diagnostic_location_line(line: &str) -> Option<usize> {
+    let location = line.trim_start().strip_prefix("--> ")?;
+    let path_and_line = location
+        .strip_prefix("src/main.rs:")
+        .or_else(|| location.strip_prefix("src/lib.rs:"))?;
+    path_and_line.split(':').next()?.parse().ok()
+}
 
-    if result.trim().is_empty() {
-        api::send_reply(args, &format!("{}``` ```", flag_parse_errors))
-    } else {
-        crate::reply_potentially_long_text(
-            args,
-            &format!("{}```rust\n{}", flag_parse_errors, result),
-            "```",
-            &format!(
-                "Output too large. Playground link: {}",
-                url_from_gist(&flags, &post_gist(args, code)?),
-            ),
-        )
-    }
+/// An error or warning, parsed from rustc's human-readable output
+#[derive(Debug, PartialEq)]
+struct Diagnostic<'a> {
+    /// `error` or `warning`
+    level: &'a str,
+    /// Like `E0308`, if the diagnostic has one
+    code: Option<&'a str>,
+    message: &'a str,
+    line: usize,
+    column: usize,
+    /// The source line the diagnostic points to, and the markers and label under it
+    source: Option<(&'a str, &'a str)>,
 }
 
-fn apply_rustfmt(text: &str, edition: Edition) -> Result<PlayResult, Error> {
-    use std::io::Write as _;
+/// Parse the diagnostics in the compiler output. Only diagnostics with a location are returned,
+/// summaries like `error: aborting due to previous error` are left out
+///
+/// ```rust
+/// let stderr = "error[E0308]: mismatched types
+///  --> src/main.rs:2:18
+///   |
+/// 2 |     let x: u32 = \"a\";
+///   |            ---   ^^^ expected `u32`, found `&str`
+///   |            |
+///   |            expected due to this
+///
+/// warning: unused variable: `y`
+///  --> src/main.rs:3:9
+///
+/// error: aborting due to previous error
+/// ";
+/// assert_eq!(
+///     parse_diagnostics(stderr),
+///     [
+///         Diagnostic {
+///             level: "error",
+///             code: Some("E0308"),
+///             message: "mismatched types",
+///             line: 2,
+///             column: 18,
+///             source: Some((
+///                 "    let x: u32 = \"a\";",
+///                 "           ---   ^^^ expected `u32`, found `&str`",
+///             )),
+///         },
+///         Diagnostic {
+///             level: "warning",
+///             code: None,
+///             message: "unused variable: `y`",
+///             line: 3,
+///             column: 9,
+///             source: None,
+///         },
+///     ],
+/// );
+/// ```
+fn parse_diagnostics(stderr: &str) -> Vec<Diagnostic<'_>> {
+    fn parse_header(line: &str) -> Option<(&str, Option<&str>, &str)> {
+        let (level, rest) = ["error", "warning"]
+            .iter()
+            .find_map(|level| Some((*level, line.strip_prefix(level)?)))?;
+        let (code, message) = match rest.strip_prefix('[') {
+            Some(rest) => {
+                let code_end = rest.find(']')?;
+                (Some(&rest[..code_end]), &rest[(code_end + 1)..])
+            }
+            None => (None, rest),
+        };
+        Some((level, code, message.strip_prefix(": ")?))
+    }
 
-    let mut child = std::process::Command::new("rustfmt")
-        .args(&[
-            "--edition",
-            match edition {
-                Edition::E2015 => "2015",
-                Edition::E2018 => "2018",
-            },
-            "--color",
-            "never",
-        ])
-        .stdin(std::process::Stdio::piped())
-        .stdout(std::process::Stdio::piped())
-        .stderr(std::process::Stdio::piped())
-        .spawn()?;
+    let mut diagnostics = Vec::new();
+    // The last header, and the diagnostic once its location was found
+    let mut header = None;
+    let mut lines = stderr.lines().peekable();
+    while let Some(line) = lines.next() {
+        if let Some(parsed_header) = parse_header(line) {
+            header = Some(parsed_header);
+            continue;
+        }
+        if let (Some((level, code, message)), Some(location)) =
+            (header, line.trim_start().strip_prefix("--> "))
+        {
+            header = None;
+            let mut location = location.rsplit(':');
+            let (column, line) = match (location.next(), location.next()) {
+                (Some(column), Some(line)) => match (column.parse(), line.parse()) {
+                    (Ok(column), Ok(line)) => (column, line),
+                    _ => continue,
+                },
+                _ => continue,
+            };
+            diagnostics.push(Diagnostic {
+                level,
+                code,
+                message,
+                line,
+                column,
+                source: None,
+            });
+            continue;
+        }
 
-    child
-        .stdin
-        .as_mut()
-        .ok_or("This can't happen, we captured by pipe")?
-        .write_all(text.as_bytes())?;
+        // The first source line after the location, if it's the line the location points to
+        let diagnostic = match diagnostics.last_mut() {
+            Some(diagnostic) if diagnostic.source.is_none() && header.is_none() => diagnostic,
+            _ => continue,
+        };
+        if let Some((number, '|', code)) = parse_diagnostic_source_line(line) {
+            let markers = lines
+                .peek()
+                .and_then(|markers| markers.trim_start().strip_prefix('|'))
+                .map(|markers| markers.strip_prefix(' ').unwrap_or(markers));
+            if let (true, Some(markers)) = (number == diagnostic.line, markers) {
+                diagnostic.source = Some((code, markers));
+            }
+        }
+    }
+    diagnostics
+}
 
-    let output = child.wait_with_output()?;
-    Ok(PlayResult {
-        stdout: String::from_utf8_lossy(&output.stdout).into_owned(),
-        stderr: String::from_utf8_lossy(&output.stderr).into_owned(),
-        success: output.status.success(),
-    })
+/// Render the diagnostics in the compiler output compactly: one line per diagnostic with its
+/// location, and the offending code with its markers below, without indentation. Returns `None` if
+/// there are no diagnostics with a location, in which case the output should be shown as it is
+///
+/// ```rust
+/// let stderr = "error[E0308]: mismatched types
+///  --> src/main.rs:2:18
+///   |
+/// 2 |     let x: u32 = \"a\";
+///   |            ---   ^^^ expected `u32`, found `&str`
+///   |            |
+///   |            expected due to this
+///
+/// error: aborting due to previous error
+/// ";
+/// assert_eq!(
+///     render_compact_diagnostics(stderr).unwrap(),
+///     "error[E0308] at 2:18: mismatched types
+/// let x: u32 = \"a\";
+///        ---   ^^^ expected `u32`, found `&str`
+/// ",
+/// );
+/// assert_eq!(render_compact_diagnostics("error: could not compile `playground`"), None);
+/// ```
+fn render_compact_diagnostics(stderr: &str) -> Option<String> {
+    let diagnostics = parse_diagnostics(stderr);
+    if diagnostics.is_empty() {
+        return None;
+    }
+
+    let mut rendered = String::new();
+    for diagnostic in diagnostics {
+        rendered += &format!(
+            "{}{} at {}:{}: {}\n",
+            diagnostic.level,
+            diagnostic
+                .code
+                .map_or(String::new(), |code| format!("[{}]", code)),
+            diagnostic.line,
+            diagnostic.column,
+            diagnostic.message,
+        );
+        if let Some((code, markers)) = diagnostic.source {
+            // the markers are aligned with the code, so they lose the same indentation
+            let indentation = code.len() - code.trim_start().len();
+            let markers = markers.get(indentation..).unwrap_or(markers);
+            rendered += &format!("{}\n{}\n", code.trim_start(), markers);
+        }
+    }
+    Some(rendered)
 }
 
-fn strip_fn_main_boilerplate_from_formatted(text: &str) -> String {
-    // Remove all the fn main boilerplate and also revert the indent introduced by rustfmt
-    let mut output = String::new();
-    for line in extract_relevant_lines(text, &["fn main() {"], &["}"]).lines() {
-        output.push_str(line.strip_prefix("    ").unwrap_or(line));
+/// Put a `L12: <code>` line above each error and warning, with the line of the user's code that it
+/// points to. The line numbers of the sent code are mapped back with `line_map`, so they match the
+/// code the user wrote
+///
+/// ```rust
+/// let (code, line_map) = wrap_code("let x: u32 = -1;\nx", ResultHandling::None);
+/// let diagnostics = "error[E0600]: cannot apply unary operator `-` to type `u32`
+///  --> src/main.rs:2:14
+///   |
+/// 2 | let x: u32 = -1;
+///   |              ^^ cannot apply unary operator `-`
+/// ";
+/// assert_eq!(
+///     annotate_diagnostics(diagnostics, &code, &line_map),
+///     format!("L1: let x: u32 = -1;\n{}", diagnostics),
+/// );
+///
+/// // diagnostics pointing at generated code aren't annotated
+/// let diagnostics = "error: unexpected closing delimiter\n --> src/main.rs:4:1\n";
+/// assert_eq!(annotate_diagnostics(diagnostics, &code, &line_map), diagnostics);
+/// ```
+fn annotate_diagnostics(diagnostics: &str, code: &str, line_map: &LineMap) -> String {
+    let code_lines = code.lines().collect::<Vec<_>>();
+    let lines = diagnostics.lines().collect::<Vec<_>>();
+
+    let mut output = String::with_capacity(diagnostics.len());
+    for (i, line) in lines.iter().enumerate() {
+        let is_header = line.starts_with("error") || line.starts_with("warning");
+        // the location marker directly follows the header
+        let annotation = lines
+            .get(i + 1)
+            .filter(|_| is_header)
+            .and_then(|next_line| {
+                let line_number = diagnostic_location_line(next_line)?;
+                let user_line = line_map.user_line(line_number)?;
+                Some(format!(
+                    "L{}: {}\n",
+                    user_line,
+                    code_lines.get(line_number - 1)?.trim()
+                ))
+            });
+        if let Some(annotation) = annotation {
+            output.push_str(&annotation);
+        }
+        output.push_str(line);
         output.push('\n');
     }
+    if !diagnostics.ends_with('\n') {
+        output.pop();
+    }
     output
 }
 
-/// Extract compiler output and program stderr output and format the two nicely
-fn format_play_eval_stderr(result: &mut PlayResult) {
-    let compiler_warnings = extract_relevant_lines(
-        &result.stderr,
-        &["Compiling playground"],
-        &[
-            "warning emitted",
-            "warnings emitted",
-            "error: aborting",
-            "Finished ",
-        ],
-    );
-    let program_stderr = match result.stderr.contains("Running `target") {
-        true => extract_relevant_lines(&result.stderr, &["Running `target"], &[]),
-        false => "",
+/// A playground result, with stderr split up into the compiler's part and the program's part
+struct PlayOutput {
+    success: bool,
+    /// Diagnostics emitted by the compiler or other tooling
+    compiler_warnings: String,
+    program_stderr: String,
+    stdout: String,
+    /// Number of warnings the compiler reported in its summary line
+    warning_count: usize,
+}
+
+impl From<PlayResult> for PlayOutput {
+    /// For tools like clippy or rustfmt, whose stderr consists entirely of diagnostics
+    fn from(result: PlayResult) -> Self {
+        Self {
+            success: result.success,
+            compiler_warnings: result.stderr,
+            program_stderr: String::new(),
+            stdout: result.stdout,
+            warning_count: 0,
+        }
+    }
+}
+
+/// Parse the number of warnings from the compiler's summary line (`N warnings emitted` from rustc
+/// or `generated N warnings` from cargo), which [`extract_relevant_lines`] usually strips
+fn count_warnings(stderr: &str) -> usize {
+    stderr
+        .lines()
+        .rev()
+        .find_map(|line| {
+            let line = line.trim_end();
+            let count = if let Some(summary_start) = line.find("generated ") {
+                line[(summary_start + "generated ".len())..]
+                    .split(' ')
+                    .next()?
+            } else {
+                line.strip_suffix(" warnings emitted")
+                    .or_else(|| line.strip_suffix(" warning emitted"))?
+                    .rsplit(' ')
+                    .next()?
+            };
+            count.parse().ok()
+        })
+        .unwrap_or(0)
+}
+
+/// Concatenate two multiline strings, separated by an empty line if both are non-empty
+fn join_sections(a: &str, b: &str) -> String {
+    match (a, b) {
+        ("", b) => b.to_owned(),
+        (a, "") => a.to_owned(),
+        (a, b) => format!("{}\n{}", a, b),
+    }
+}
+
+/// Best-effort detection of code that doesn't compile on older editions. Returns the oldest edition
+/// the code needs, together with what needs it
+///
+/// ```rust
+/// assert_eq!(required_edition("fn main() { println!(\"{}\", 1); }"), None);
+/// assert_eq!(
+///     required_edition("async fn f() {}"),
+///     Some((Edition::E2018, "`async`/`.await`")),
+/// );
+/// assert_eq!(required_edition("let asynchronous = 1;"), None);
+///
+/// let code = "let x: u8 = 300u32.try_into().unwrap();";
+/// assert_eq!(required_edition(code).map(|(e, _)| e), Some(Edition::E2021));
+/// // with an explicit import, `try_into` works on all editions
+/// let code = "use std::convert::TryInto;\nlet x: u8 = 300u32.try_into().unwrap();";
+/// assert_eq!(required_edition(code), None);
+/// // neither does a method of the same name that the code defines itself
+/// let code = "struct A;\nimpl A { fn try_into(self) -> u8 { 1 } }\nlet x = A.try_into();";
+/// assert_eq!(required_edition(code), None);
+///
+/// // a `use` path relative to a module that isn't at the crate root
+/// let code = "mod util { pub fn f() {} }\nuse util::f;\nf();";
+/// assert_eq!(required_edition(code).map(|(e, _)| e), Some(Edition::E2018));
+/// // in 2015, `use` paths start at the crate root, so this works there as well
+/// let code = "mod util { pub fn f() {} }\nuse util::f;\nfn main() { f(); }";
+/// assert_eq!(required_edition(code), None);
+/// // and so does an explicit `self::`
+/// let code = "mod a {\n    mod b { pub fn f() {} }\n    use self::b::f;\n}\nfn main() {}";
+/// assert_eq!(required_edition(code), None);
+/// ```
+fn required_edition(code: &str) -> Option<(Edition, &'static str)> {
+    let is_ident_char = |c: char| c.is_alphanumeric() || c == '_';
+    let uses_word = |word: &str| {
+        code.match_indices(word).any(|(i, _)| {
+            !code[..i].ends_with(is_ident_char)
+                && !code[(i + word.len())..].starts_with(is_ident_char)
+        })
+    };
+    let is_imported = |name: &str| {
+        code.lines()
+            .any(|line| line.trim_start().starts_with("use ") && line.contains(name))
+    };
+    let defines = |name: &str| {
+        ["fn", "trait", "struct", "enum", "type"]
+            .iter()
+            .any(|keyword| uses_word(&format!("{} {}", keyword, name)))
+    };
+
+    // These traits were only added to the prelude in 2021
+    let prelude_2021_traits = [
+        ("TryFrom", "try_from"),
+        ("TryInto", "try_into"),
+        ("FromIterator", "from_iter"),
+    ];
+    let needs_2021_prelude = prelude_2021_traits.iter().any(|(name, method)| {
+        (uses_word(name) || uses_word(method))
+            && !is_imported(name)
+            && !defines(name)
+            && !defines(method)
+    });
+    if needs_2021_prelude {
+        return Some((
+            Edition::E2021,
+            "using `TryFrom`, `TryInto` or `FromIterator` without importing them",
+        ));
+    }
+
+    if uses_word("async") || code.contains(".await") {
+        return Some((Edition::E2018, "`async`/`.await`"));
+    }
+
+    // In 2015, `use` paths always start at the crate root, so modules declared anywhere else
+    // (including inside the `fn main` that the code gets wrapped in) can't be imported without
+    // spelling out the full path
+    let is_wrapped = !code.contains("fn main");
+    let nested_modules = code
+        .lines()
+        .filter(|line| is_wrapped || line.starts_with(char::is_whitespace))
+        .filter_map(|line| {
+            let declaration = line.trim_start();
+            let declaration = declaration.strip_prefix("pub ").unwrap_or(declaration);
+            let name = declaration.strip_prefix("mod ")?;
+            name.split(|c: char| !is_ident_char(c)).next()
+        })
+        .filter(|name| !name.is_empty())
+        .collect::<Vec<_>>();
+    let uses_relative_path = code.lines().any(|line| {
+        let path = match line.trim_start().strip_prefix("use ") {
+            Some(path) => path,
+            None => return false,
+        };
+        nested_modules
+            .iter()
+            .any(|name| path.starts_with(&format!("{}::", name)))
+    });
+    if uses_relative_path {
+        return Some((
+            Edition::E2018,
+            "`use` paths that are relative to a local module",
+        ));
+    }
+
+    None
+}
+
+/// The note for when the output doesn't fit into the reply, with a playground link to the code so
+/// users can see the full output there
+fn output_too_large_message(
+    args: &Args,
+    flags: &CommandFlags,
+    code: &str,
+    output: &str,
+) -> Result<String, Error> {
+    Ok(format!(
+        "Output too large ({} lines, {} characters). Playground link: {}",
+        output.lines().count(),
+        output.chars().count(),
+        url_from_gist(flags, &post_gist(args, code)?),
+    ))
+}
+
+/// With showsource=true, send the code as it was sent to the playground, including the `fn main`
+/// and other code generated around the user's code, in an additional message. If it's too long,
+/// it's linked instead
+fn send_generated_source(args: &Args, flags: &CommandFlags, code: &str) -> Result<(), Error> {
+    if !flags.show_source {
+        return Ok(());
+    }
+
+    let reply = format!("Generated code:\n```rust\n{}\n```", code);
+    if reply.chars().count() > crate::DISCORD_MESSAGE_LIMIT {
+        let url = url_from_gist(flags, &post_gist(args, code)?);
+        args.msg.channel_id.say(
+            args.cx,
+            format!("The generated code is too long to show: {}", url),
+        )?;
+    } else {
+        args.msg.channel_id.say(args.cx, reply)?;
+    }
+    Ok(())
+}
+
+/// Whether `play`, `eval`, `clippy` and `miri` react with ✅ or ❌ to the command message, so the
+/// outcome is visible at a glance in busy channels. Set to false to avoid the reaction noise
+const REACT_WITH_RESULT_STATUS: bool = true;
+
+/// React to the command message with ✅ or ❌ depending on whether the code ran successfully. If
+/// the command was edited or re-run, the opposite reaction from the previous run is removed first.
+/// Must be called before sending the reply. Errors are only logged, because missing permissions
+/// for reactions shouldn't break the command
+fn react_result_status(args: &Args, success: bool) {
+    if !REACT_WITH_RESULT_STATUS {
+        return;
+    }
+
+    let (status, stale_status) = match success {
+        true => ('✅', '❌'),
+        false => ('❌', '✅'),
+    };
+    if api::response_exists(args).is_some() {
+        if let Err(e) =
+            args.msg
+                .channel_id
+                .delete_reaction(&args.cx.http, args.msg.id, None, stale_status)
+        {
+            warn!("Can't remove stale status reaction: {}", e);
+        }
+    }
+    if let Err(e) = args.msg.react(&args.cx.http, status) {
+        warn!("Can't react with result status: {}", e);
+    }
+}
+
+/// Send a Discord reply with the formatted contents of a Playground result
+fn send_reply(
+    args: &Args<'_>,
+    result: PlayOutput,
+    code: &str,
+    flags: &CommandFlags,
+    flag_parse_errors: &str,
+) -> Result<(), Error> {
+    send_reply_with_lang(args, result, code, flags, flag_parse_errors, "rust", "")
+}
+
+/// Like [`send_reply`], but with a custom code block language for the output and a footer that's
+/// appended after the output code block
+fn send_reply_with_lang(
+    args: &Args<'_>,
+    mut result: PlayOutput,
+    code: &str,
+    flags: &CommandFlags,
+    flag_parse_errors: &str,
+    lang: &str,
+    footer: &str,
+) -> Result<(), Error> {
+    let mut flag_parse_errors = flag_parse_errors.to_owned();
+    match flags.opt_level {
+        None | Some(OptLevel::O0) | Some(OptLevel::O3) => {}
+        Some(opt_level) => {
+            flag_parse_errors += &format!(
+                "The playground can't compile with opt_level={}, so release mode (opt-level 3) \
+                was used instead\n",
+                opt_level
+            )
+        }
+    }
+    if let PanicStrategy::Abort = flags.panic {
+        flag_parse_errors +=
+            "The playground can't compile with panic=abort, so panics unwind as usual\n";
+    }
+    if let Some((edition, reason)) = required_edition(code) {
+        if flags.edition < edition {
+            flag_parse_errors += &format!(
+                "Hint: {} needs edition {} or newer, try edition={}\n",
+                reason, edition, edition
+            );
+        }
+    }
+
+    if flags.compact_diagnostics {
+        if let Some(compact) = render_compact_diagnostics(&result.compiler_warnings) {
+            result.compiler_warnings = compact;
+        }
+    }
+
+    let footer = &match flags.random_channel {
+        true => format!("{}\n(ran on {}, picked at random)", footer, flags.channel),
+        false => footer.to_owned(),
+    };
+
+    let flag_parse_errors = &match (result.success, result.warning_count) {
+        (false, _) | (true, 0) => flag_parse_errors,
+        (true, 1) => format!("{}⚠ 1 warning\n", flag_parse_errors),
+        (true, n) => format!("{}⚠ {} warnings\n", flag_parse_errors, n),
+    };
+
+    // The first section is compiler warnings on success. On failure, compile errors are in the
+    // compiler output and panics in the program stderr, so together they're the errors section.
+    // The program's stdout comes first then, since what it printed before crashing helps finding
+    // where it went wrong. With sections=true, the compiler output always comes first instead
+    let program_output = join_sections(&result.program_stderr, &result.stdout);
+    let errors = join_sections(&result.compiler_warnings, &result.program_stderr);
+    let sections = if flags.sections {
+        format_sections(
+            ("=== compiler ===", "rust", &result.compiler_warnings),
+            ("=== output ===", lang, &program_output),
+            true,
+        )
+    } else if result.success {
+        format_sections(
+            ("Warnings:", "rust", &result.compiler_warnings),
+            ("Output:", lang, &program_output),
+            false,
+        )
+    } else {
+        format_sections(
+            ("Output:", lang, &result.stdout),
+            ("Errors:", lang, &errors),
+            false,
+        )
+    };
+
+    let text_body = match sections {
+        Some(sections) => format!("{}{}", flag_parse_errors, sections),
+        None => return api::send_reply(args, &format!("{}``` ```{}", flag_parse_errors, footer)),
+    };
+
+    let output = LastOutput {
+        text_end: format!("```{}", footer),
+        truncation_msg: output_too_large_message(args, flags, code, &text_body)?,
+        text_body,
+    };
+    crate::reply_paginated_long_text(
+        args,
+        &output.text_body,
+        &output.text_end,
+        &output.truncation_msg,
+    )?;
+
+    if result.success {
+        let mut data = args.cx.data.write();
+        let last_outputs = data.get_mut::<LastOutputs>().unwrap();
+        last_outputs.retain(|_, (sent, _)| sent.elapsed() < LAST_OUTPUT_TTL);
+        last_outputs.insert(
+            (args.msg.author.id, args.msg.channel_id),
+            (Instant::now(), output),
+        );
+    }
+    Ok(())
+}
+
+pub fn last(args: &Args) -> Result<(), Error> {
+    let output = {
+        let data = args.cx.data.read();
+        let last_outputs = data.get::<LastOutputs>().unwrap();
+        match last_outputs.get(&(args.msg.author.id, args.msg.channel_id)) {
+            Some((sent, output)) if sent.elapsed() < LAST_OUTPUT_TTL => Some(output.clone()),
+            _ => None,
+        }
+    };
+
+    // send_reply locks the data for writing, so the read lock has to be released by now
+    match output {
+        Some(output) => crate::reply_paginated_long_text(
+            args,
+            &output.text_body,
+            &output.text_end,
+            &output.truncation_msg,
+        ),
+        None => api::send_reply(args, "No recent output"),
+    }
+}
+
+pub fn last_help(args: &Args) -> Result<(), Error> {
+    api::send_reply(
+        args,
+        "Send your most recent successful output in this channel again, if it's from the last \
+        30 minutes. Useful when it scrolled away.
+```?last```",
+    )
+}
+
+/// Lay out the two sections of a reply, given as label, code block language and text, as labeled
+/// code blocks. Empty sections are left out, and a lone second section is only labeled if
+/// `label_lone_second` is set. The last code block is left open, so that it can be closed after
+/// truncating the text. Returns `None` if both sections are empty
+///
+/// ```rust
+/// let warnings = ("Warnings:", "rust", "warning: unused variable\n");
+/// let output = ("Output:", "", "hello\n");
+/// assert_eq!(
+///     format_sections(warnings, output, false).as_deref(),
+///     Some("Warnings:\n```rust\nwarning: unused variable\n```\nOutput:\n```\nhello\n"),
+/// );
+/// assert_eq!(
+///     format_sections(warnings, ("Output:", "", ""), false).as_deref(),
+///     Some("Warnings:\n```rust\nwarning: unused variable\n"),
+/// );
+/// assert_eq!(
+///     format_sections(("Warnings:", "rust", "\n"), output, false).as_deref(),
+///     Some("```\nhello\n"),
+/// );
+/// assert_eq!(
+///     format_sections(("Warnings:", "rust", ""), output, true).as_deref(),
+///     Some("Output:\n```\nhello\n"),
+/// );
+/// assert_eq!(format_sections(("Warnings:", "rust", ""), ("Output:", "", ""), true), None);
+/// ```
+fn format_sections(
+    (first_label, first_lang, first): (&str, &str, &str),
+    (second_label, second_lang, second): (&str, &str, &str),
+    label_lone_second: bool,
+) -> Option<String> {
+    // If this gets truncated inside the first section, the second section is omitted entirely
+    // and the code block is closed after the truncated first section, so it stays well-formed
+    Some(match (first.trim().is_empty(), second.trim().is_empty()) {
+        (true, true) => return None,
+        (true, false) if !label_lone_second => format!("```{}\n{}", second_lang, second),
+        (true, false) => format!("{}\n```{}\n{}", second_label, second_lang, second),
+        (false, true) => format!("{}\n```{}\n{}", first_label, first_lang, first),
+        (false, false) => format!(
+            "{}\n```{}\n{}```\n{}\n```{}\n{}",
+            first_label, first_lang, first, second_label, second_lang, second
+        ),
+    })
+}
+
+/// Stable rustfmt options that users may set with `config=`. Only allowing known options keeps
+/// arbitrary text out of the rustfmt arguments
+const RUSTFMT_OPTIONS: &[&str] = &[
+    "max_width",
+    "hard_tabs",
+    "tab_spaces",
+    "newline_style",
+    "use_small_heuristics",
+    "fn_call_width",
+    "attr_fn_like_width",
+    "struct_lit_width",
+    "struct_variant_width",
+    "array_width",
+    "chain_width",
+    "single_line_if_else_max_width",
+    "fn_params_layout",
+    "match_arm_leading_pipes",
+    "match_block_trailing_comma",
+    "merge_derives",
+    "remove_nested_parens",
+    "reorder_imports",
+    "reorder_modules",
+    "use_field_init_shorthand",
+    "use_try_shorthand",
+    "force_explicit_abi",
+];
+
+/// Parse a comma-separated list of `key=value` rustfmt options into the value of rustfmt's
+/// `--config` argument. Unknown options and invalid values are skipped and reported in `errors`
+///
+/// ```rust
+/// let mut errors = String::new();
+/// assert_eq!(
+///     parse_rustfmt_config("max_width=80,hard_tabs=true", &mut errors),
+///     "max_width=80,hard_tabs=true",
+/// );
+/// assert_eq!(errors, "");
+///
+/// let config = parse_rustfmt_config("foo=1,tab_spaces=2,max_width=--help,edition", &mut errors);
+/// assert_eq!(config, "tab_spaces=2");
+/// assert_eq!(
+///     errors,
+///     "unknown rustfmt option `foo`\n\
+///     invalid value `--help` for rustfmt option `max_width`\n\
+///     rustfmt option `edition` needs a value, like `edition=value`\n",
+/// );
+/// ```
+fn parse_rustfmt_config(config: &str, errors: &mut String) -> String {
+    let mut options = Vec::new();
+    for option in config.split(',') {
+        let mut key_value = option.splitn(2, '=');
+        let (key, value) = match (key_value.next(), key_value.next()) {
+            (Some(key), Some(value)) => (key, value),
+            _ => {
+                *errors += &format!(
+                    "rustfmt option `{0}` needs a value, like `{0}=value`\n",
+                    option
+                );
+                continue;
+            }
+        };
+
+        if !RUSTFMT_OPTIONS.contains(&key) {
+            *errors += &format!("unknown rustfmt option `{}`\n", key);
+        } else if value.is_empty() || !value.chars().all(|c| c.is_ascii_alphanumeric()) {
+            *errors += &format!("invalid value `{}` for rustfmt option `{}`\n", value, key);
+        } else {
+            options.push(option);
+        }
+    }
+    options.join(",")
+}
+
+/// Run a rustfmt binary on the text. Kept separate from [`apply_rustfmt`], so that the error
+/// for a missing binary can be told apart
+///
+/// ```rust
+/// let error = run_rustfmt("rustfmt-that-does-not-exist", "fn main() {}", &[]).unwrap_err();
+/// assert_eq!(error.kind(), std::io::ErrorKind::NotFound);
+/// ```
+fn run_rustfmt(binary: &str, text: &str, rustfmt_args: &[&str]) -> std::io::Result<PlayResult> {
+    use std::io::Write as _;
+
+    let mut child = std::process::Command::new(binary)
+        .args(rustfmt_args)
+        .stdin(std::process::Stdio::piped())
+        .stdout(std::process::Stdio::piped())
+        .stderr(std::process::Stdio::piped())
+        .spawn()?;
+
+    child
+        .stdin
+        .as_mut()
+        .ok_or_else(|| std::io::Error::other("This can't happen, we captured by pipe"))?
+        .write_all(text.as_bytes())?;
+
+    let output = child.wait_with_output()?;
+    Ok(PlayResult {
+        stdout: String::from_utf8_lossy(&output.stdout).into_owned(),
+        stderr: String::from_utf8_lossy(&output.stderr).into_owned(),
+        success: output.status.success(),
+    })
+}
+
+#[derive(Debug, Serialize)]
+struct FormatRequest<'a> {
+    code: &'a str,
+    edition: Edition,
+}
+
+#[derive(Debug, Deserialize)]
+struct FormatResponse {
+    success: bool,
+    code: String,
+    stderr: String,
+}
+
+/// Format the code with the local rustfmt. `config` is passed to rustfmt's `--config` argument,
+/// see [`parse_rustfmt_config`]. If rustfmt isn't installed, the playground formats the code
+/// instead, without the config
+fn apply_rustfmt(
+    args: &Args,
+    text: &str,
+    edition: Edition,
+    config: &str,
+) -> Result<PlayResult, Error> {
+    let edition_arg = edition.to_string();
+    let mut rustfmt_args = vec!["--edition", &edition_arg, "--color", "never"];
+    if !config.is_empty() {
+        rustfmt_args.extend(&["--config", config]);
+    }
+
+    match run_rustfmt("rustfmt", text, &rustfmt_args) {
+        Ok(result) => Ok(result),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+            error!("rustfmt isn't installed on this host, formatting on the playground instead");
+
+            count_playground_request(args);
+            let response = post_with_retry(
+                args.http
+                    .post("https://play.rust-lang.org/format")
+                    .timeout(PLAYGROUND_TIMEOUT),
+                &FormatRequest {
+                    code: text,
+                    edition,
+                },
+            )
+            .map_err(|e| {
+                format!(
+                    "rustfmt isn't installed on the bot's host, and the playground couldn't \
+                    format the code either: {}",
+                    e
+                )
+            })?;
+            let status = response.status();
+            let result: FormatResponse = parse_playground_response(status, &response.text()?)?;
+            Ok(PlayResult {
+                success: result.success,
+                stdout: result.code,
+                stderr: result.stderr,
+            })
+        }
+        Err(e) => Err(e.into()),
+    }
+}
+
+fn strip_fn_main_boilerplate_from_formatted(text: &str) -> String {
+    // Remove all the fn main boilerplate and also revert the indent introduced by rustfmt
+    let mut output = String::new();
+    for line in extract_relevant_lines(text, &["fn main() {"], &["}"]).lines() {
+        output.push_str(line.strip_prefix("    ").unwrap_or(line));
+        output.push('\n');
+    }
+    output
+}
+
+/// Remove ANSI escape sequences like color codes, which Discord would display as garbage
+///
+/// ```rust
+/// assert_eq!(strip_ansi("\x1b[1m\x1b[31merror\x1b[0m: oops"), "error: oops");
+/// assert_eq!(strip_ansi("\x1b[1;38;5;12m-->\x1b[0m src/main.rs"), "--> src/main.rs");
+/// assert_eq!(strip_ansi("let a = [1, 2][0];"), "let a = [1, 2][0];");
+/// ```
+fn strip_ansi(text: &str) -> String {
+    match strip_ansi_escapes::strip(text) {
+        Ok(stripped) => String::from_utf8_lossy(&stripped).into_owned(),
+        // can't happen, we're writing into a Vec
+        Err(_) => text.to_owned(),
+    }
+}
+
+/// Extract compiler output and program stderr output and separate the two
+fn format_play_eval_stderr(result: PlayResult) -> PlayOutput {
+    let stderr = strip_ansi(&result.stderr);
+
+    // Only look for the compiler end tokens in the compiler part of stderr, otherwise program
+    // output like backtraces could be mistaken for compiler output
+    let compiler_stderr = match stderr.find("Running `target") {
+        Some(running_pos) => &stderr[..running_pos],
+        None => &stderr,
+    };
+    let compiler_warnings = extract_relevant_lines(
+        compiler_stderr,
+        &["Compiling playground"],
+        &[
+            "warning emitted",
+            "warnings emitted",
+            "error: aborting",
+            "Finished ",
+        ],
+    );
+    let program_stderr = match stderr.contains("Running `target") {
+        true => extract_relevant_lines(&stderr, &["Running `target"], &[]),
+        false => "",
+    };
+
+    PlayOutput {
+        success: result.success,
+        compiler_warnings: compiler_warnings.to_owned(),
+        program_stderr: rewrite_timeout_message(program_stderr).into_owned(),
+        stdout: strip_ansi(&result.stdout),
+        warning_count: count_warnings(compiler_stderr),
+    }
+}
+
+/// The playground kills programs that exceed its time limit, which only shows up as a shell message
+/// about the killed process in stderr. Replace that message with an explanation, keeping what the
+/// program printed before
+///
+/// ```rust
+/// let stderr = "     Running `target/debug/playground`
+/// counting...
+/// /playground/tools/entrypoint.sh: line 11:     8 Killed                  timeout --signal=KILL ${timeout} \"$@\"";
+/// assert_eq!(
+///     rewrite_timeout_message(stderr),
+///     "     Running `target/debug/playground`
+/// counting...
+/// Your program exceeded the execution time limit (likely an infinite loop or too much work).",
+/// );
+///
+/// let stderr = "thread 'main' panicked at 'oops', src/main.rs:1:13";
+/// assert_eq!(rewrite_timeout_message(stderr), stderr);
+/// ```
+fn rewrite_timeout_message(stderr: &str) -> Cow<'_, str> {
+    if !stderr.contains("timeout --signal=KILL") {
+        return Cow::Borrowed(stderr);
+    }
+
+    let lines = stderr
+        .lines()
+        .map(|line| match line.contains("timeout --signal=KILL") {
+            true => {
+                "Your program exceeded the execution time limit (likely an infinite loop or too \
+                much work)."
+            }
+            false => line,
+        })
+        .collect::<Vec<_>>();
+    Cow::Owned(lines.join("\n"))
+}
+
+/// Disable the `#[test]` functions whose name doesn't contain `filter`, like `cargo test <filter>`
+/// does. The playground doesn't pass arguments to the test harness, so the other tests are
+/// compiled out with `#[cfg(any())]` instead. Returns the filtered code and how many tests matched
+/// and were filtered out
+///
+/// ```rust
+/// let code = "#[test]\nfn parses() {}\n#[test] fn prints() {}\nfn helper() {}";
+/// assert_eq!(
+///     filter_tests(code, "pars"),
+///     (
+///         "#[test]\nfn parses() {}\n#[cfg(any())] fn prints() {}\nfn helper() {}".to_owned(),
+///         1,
+///         1
+///     ),
+/// );
+/// assert_eq!(filter_tests(code, "p").1, 2);
+/// ```
+fn filter_tests(code: &str, filter: &str) -> (String, usize, usize) {
+    let mut output = String::with_capacity(code.len());
+    let mut matched = 0;
+    let mut filtered_out = 0;
+    let mut rest = code;
+    while let Some(attribute_start) = rest.find("#[test]") {
+        let (before, after) = rest.split_at(attribute_start);
+        output.push_str(before);
+        rest = &after["#[test]".len()..];
+
+        let name = rest.find("fn ").and_then(|fn_start| {
+            rest[(fn_start + 3)..]
+                .trim_start()
+                .split(|c: char| !(c.is_alphanumeric() || c == '_'))
+                .next()
+        });
+        if name.is_some_and(|name| name.contains(filter)) {
+            output.push_str("#[test]");
+            matched += 1;
+        } else {
+            output.push_str("#[cfg(any())]");
+            filtered_out += 1;
+        }
+    }
+    output.push_str(rest);
+    (output, matched, filtered_out)
+}
+
+/// Extract compiler output and the test harness report. The report is treated as program stderr,
+/// so that it's shown even if the run failed because of failing tests. `filtered_out` is the
+/// number of tests disabled by [`filter_tests`], which the harness itself doesn't know about
+fn format_test_output(result: PlayResult, filtered_out: usize) -> PlayOutput {
+    let stderr = strip_ansi(&result.stderr);
+    let stdout = strip_ansi(&result.stdout);
+    let compiler_warnings = extract_relevant_lines(
+        &stderr,
+        &["Compiling playground"],
+        &[
+            "warning emitted",
+            "warnings emitted",
+            "error: aborting",
+            "Finished ",
+        ],
+    );
+
+    // Only keep the first harness run, from the "running N tests" banner up to and including the
+    // "test result:" summary. This drops the (always empty) doc-tests run
+    let test_report = match stdout.find("running ") {
+        Some(report_start) => {
+            let report = &stdout[report_start..];
+            let report_end = report
+                .find("test result:")
+                .and_then(|summary_start| {
+                    report[summary_start..]
+                        .find('\n')
+                        .map(|line_end| summary_start + line_end + 1)
+                })
+                .unwrap_or(report.len());
+            &report[..report_end]
+        }
+        None => "",
+    };
+    let test_report = test_report.replace(
+        "; 0 filtered out",
+        &format!("; {} filtered out", filtered_out),
+    );
+
+    PlayOutput {
+        success: result.success,
+        compiler_warnings: compiler_warnings.to_owned(),
+        program_stderr: test_report,
+        stdout: String::new(),
+        warning_count: count_warnings(&stderr),
+    }
+}
+
+// ================================
+// ACTUAL BOT COMMANDS BEGIN HERE
+// ================================
+
+/// play and eval work similarly, so this function abstracts over the two.
+/// `crate_attributes` is put in front of the user code, where maybe_wrap keeps it at the top of the
+/// crate
+fn play_or_eval(
+    args: &Args,
+    result_handling: ResultHandling,
+    crate_attributes: &str,
+) -> Result<(), Error> {
+    let user_code = extract_code_or_gist(args)?;
+    let (flags, mut flag_parse_errors) = parse_flags(args, &user_code);
+    let (user_code, env_vars) = extract_env_vars(&user_code, &mut flag_parse_errors);
+    let code = crate_attributes.to_owned() + &user_code;
+    check_manifest(args, &flags, &mut flag_parse_errors)?;
+    let result_handling = match result_handling {
+        ResultHandling::Print if flags.pretty => ResultHandling::PrettyPrint,
+        other => other,
+    };
+    if is_no_std(&user_code) {
+        if let ResultHandling::Print | ResultHandling::PrettyPrint = result_handling {
+            flag_parse_errors += "`#![no_std]` code can't print its result, because `println!` \
+                needs std, so it's run as it is\n";
+        }
+    }
+
+    if !flags.run {
+        return check_code(
+            args,
+            crate_attributes,
+            &user_code,
+            result_handling,
+            &flags,
+            &flag_parse_errors,
+            "Compiled successfully, not run",
+        );
+    }
+
+    let (code, mut line_map) = wrap_code(&code, result_handling);
+    line_map.skip_prepended_lines(crate_attributes.lines().count());
+    let code = match inject_env_vars(&code, &env_vars, flags.edition) {
+        Some(code) => code,
+        None => {
+            flag_parse_errors +=
+                "The `// env:` variables can't be set, because the code has no main function\n";
+            code.into_owned()
+        }
+    };
+
+    if let Some(target) = flags.target {
+        return compile_to(args, &code, target, &flags, &flag_parse_errors);
+    }
+
+    if has_endless_loop(&code) {
+        flag_parse_errors +=
+            "Hint: the code has a `loop` without `break`, so it may run until the playground's \
+            timeout\n";
+    }
+
+    let sandboxed_apis = sandboxed_apis(&user_code);
+    if !sandboxed_apis.is_empty() {
+        flag_parse_errors += &format!(
+            "Note: the playground has no network access and only limited filesystem and process \
+            access, so `{}` may not work as expected\n",
+            sandboxed_apis.join("`, `")
+        );
+    }
+
+    let start = Instant::now();
+    let result: PlayResult = cached_post(
+        args,
+        &flags,
+        "https://play.rust-lang.org/execute",
+        &PlaygroundRequest {
+            code: &code,
+            channel: flags.channel,
+            crate_type: crate_type(&flags, &code),
+            edition: flags.edition,
+            mode: flags.mode,
+            tests: false,
+            backtrace: flags.backtrace,
+        },
+    )?;
+    let elapsed = start.elapsed();
+
+    let footer = if flags.timing && result.success {
+        format!("\n(took {:.1}s)", elapsed.as_secs_f64())
+    } else {
+        String::new()
+    };
+
+    let mut output = format_play_eval_stderr(result);
+    output.compiler_warnings = line_map.map_diagnostics(&annotate_diagnostics(
+        &output.compiler_warnings,
+        &code,
+        &line_map,
+    ));
+    output.program_stderr = line_map.map_diagnostics(&output.program_stderr);
+
+    react_result_status(args, output.success);
+    send_reply_with_lang(
+        args,
+        output,
+        &code,
+        &flags,
+        &flag_parse_errors,
+        "rust",
+        &footer,
+    )?;
+    send_generated_source(args, &flags, &code)
+}
+
+pub fn play(args: &Args) -> Result<(), Error> {
+    play_or_eval(args, ResultHandling::None, "")
+}
+
+pub fn eval(args: &Args) -> Result<(), Error> {
+    play_or_eval(args, ResultHandling::Print, "")
+}
+
+pub fn play_warn(args: &Args) -> Result<(), Error> {
+    play_or_eval(args, ResultHandling::None, "#![deny(warnings)]\n")
+}
+
+pub fn play_warn_help(args: &Args) -> Result<(), Error> {
+    let desc = "Compile and run Rust code with all warnings turned into errors, so that none of \
+        them go unnoticed";
+    generic_help(args, "playwarn", desc, true, "code")
+}
+
+pub fn play_and_eval_help(args: &Args, name: &str) -> Result<(), Error> {
+    let mut desc =
+        "Compile and run Rust code. Instead of a code block, you can also pass a playground \
+        link or a GitHub gist link to run the code of its gist. The dependencies can be put in a \
+        \\`\\`\\`toml code block with a [dependencies] section, which is checked against the \
+        crates available on the playground. Environment variables for the program can be set \
+        with `// env: KEY=VALUE` comment lines"
+            .to_owned();
+    if name == "eval" {
+        desc += ". With pretty=true, the result is printed with `{:#?}` instead of `{:?}`";
+    }
+    generic_help(args, name, &desc, true, "code")
+}
+
+pub fn test(args: &Args) -> Result<(), Error> {
+    let code = crate::extract_code(args.body)?;
+    let (flags, flag_parse_errors) = parse_flags(args, &code);
+    let (code, line_map) = wrap_code(&code, ResultHandling::Test);
+
+    // Like with `cargo test <filter>`, a name before the code only runs the tests that contain it
+    let filter = args.body[..args.body.find('`').unwrap_or(0)].trim();
+    let (code, filtered_out) = if filter.is_empty() {
+        (code.into_owned(), 0)
+    } else if !filter.chars().all(|c| c.is_alphanumeric() || c == '_') {
+        return crate::api::send_reply(args, &format!("`{}` isn't a valid test name", filter));
+    } else {
+        match filter_tests(&code, filter) {
+            (_, 0, _) => {
+                return crate::api::send_reply(
+                    args,
+                    &format!("No test function matches `{}`", filter),
+                )
+            }
+            (code, _, filtered_out) => (code, filtered_out),
+        }
+    };
+
+    let result: PlayResult = cached_post(
+        args,
+        &flags,
+        "https://play.rust-lang.org/execute",
+        &PlaygroundRequest {
+            code: &code,
+            channel: flags.channel,
+            crate_type: crate_type(&flags, &code),
+            edition: flags.edition,
+            mode: flags.mode,
+            tests: true,
+            backtrace: flags.backtrace,
+        },
+    )?;
+
+    let mut output = format_test_output(result, filtered_out);
+    output.compiler_warnings = line_map.map_diagnostics(&output.compiler_warnings);
+    output.program_stderr = line_map.map_diagnostics(&output.program_stderr);
+
+    send_reply(args, output, &code, &flags, &flag_parse_errors)
+}
+
+pub fn test_help(args: &Args) -> Result<(), Error> {
+    let desc = "Run the #[test] functions in the code. If there are none, the code is wrapped in a test function. \
+        Like `cargo test <name>`, `?test <name> code` only runs the tests whose name contains `name`";
+    generic_help(args, "test", desc, true, "code")
+}
+
+/// Cut away the parts of the assembly that only matter to the assembler: the preamble up to the
+/// `.file` directive and the section metadata at the end
+///
+/// ```rust
+/// let asm = "\t.text\n\t.file\t\"playground.1-cgu.0\"\nplayground::square:\n\timul edi, edi\n\tret\n\n\
+///     \t.section\t\".note.GNU-stack\",\"\",@progbits\n";
+/// assert_eq!(strip_asm_boilerplate(asm), "playground::square:\n\timul edi, edi\n\tret\n");
+///
+/// // already filtered by the playground
+/// let asm = "playground::square:\n\timul edi, edi\n\tret\n";
+/// assert_eq!(strip_asm_boilerplate(asm), asm);
+/// ```
+fn strip_asm_boilerplate(asm: &str) -> &str {
+    extract_relevant_lines(asm, &["\t.file\t"], &["\t.section\t\".note.GNU-stack\""])
+}
+
+// asm, llvm_ir and mir work the same way, they only differ in the requested target
+fn compile_to(
+    args: &Args,
+    code: &str,
+    target: Target,
+    flags: &CommandFlags,
+    flag_parse_errors: &str,
+) -> Result<(), Error> {
+    let result: CompileResult = cached_post(
+        args,
+        flags,
+        "https://play.rust-lang.org/compile",
+        &CompileRequest {
+            target,
+            assembly_flavor: "intel",
+            demangle_assembly: "demangle",
+            process_assembly: "filter",
+            code,
+            channel: flags.channel,
+            crate_type: crate_type(flags, code),
+            edition: flags.edition,
+            mode: flags.mode,
+            tests: false,
+        },
+    )?;
+
+    let stdout = match target {
+        Target::Asm => strip_asm_boilerplate(&result.code).to_owned(),
+        _ => result.code,
+    };
+    let result = format_play_eval_stderr(PlayResult {
+        success: result.success,
+        stdout,
+        stderr: result.stderr,
+    });
+
+    // on failure, only the compiler errors are shown
+    let lang = if result.success {
+        target.code_block_lang()
+    } else {
+        "rust"
+    };
+    send_reply_with_lang(args, result, code, flags, flag_parse_errors, lang, "")
+}
+
+pub fn asm(args: &Args) -> Result<(), Error> {
+    // not wrapped, otherwise the user's functions would end up nested inside fn main
+    let code = crate::extract_code(args.body)?;
+    let (flags, flag_parse_errors) = parse_flags(args, &code);
+    compile_to(args, &code, Target::Asm, &flags, &flag_parse_errors)
+}
+
+pub fn asm_help(args: &Args) -> Result<(), Error> {
+    let desc = "Show the assembly generated for the code. Compile with mode=release to see optimized assembly";
+    generic_help(args, "asm", desc, true, "code")
+}
+
+pub fn llvm_ir(args: &Args) -> Result<(), Error> {
+    let code = crate::extract_code(args.body)?;
+    let (flags, flag_parse_errors) = parse_flags(args, &code);
+    let code = maybe_wrap(&code, ResultHandling::None);
+    compile_to(args, &code, Target::LlvmIr, &flags, &flag_parse_errors)
+}
+
+pub fn llvm_ir_help(args: &Args) -> Result<(), Error> {
+    let desc = "Show the LLVM IR generated for the code";
+    generic_help(args, "llvmir", desc, true, "code")
+}
+
+pub fn mir(args: &Args) -> Result<(), Error> {
+    let code = crate::extract_code(args.body)?;
+    let (flags, flag_parse_errors) = parse_flags(args, &code);
+    let code = maybe_wrap(&code, ResultHandling::None);
+    compile_to(args, &code, Target::Mir, &flags, &flag_parse_errors)
+}
+
+pub fn mir_help(args: &Args) -> Result<(), Error> {
+    let desc = "Show the MIR (mid-level intermediate representation) generated for the code";
+    generic_help(args, "mir", desc, true, "code")
+}
+
+pub fn shorten(args: &Args) -> Result<(), Error> {
+    let code = crate::extract_code(args.body)?;
+    if code.is_empty() {
+        return api::send_reply(
+            args,
+            "There's no code to share in that code block :thinking:",
+        );
+    }
+    let (flags, flag_parse_errors) = parse_flags(args, &code);
+
+    let url = url_from_gist(&flags, &post_gist(args, &code)?);
+    api::send_reply(args, &format!("{}{}", flag_parse_errors, url))
+}
+
+pub fn shorten_help(args: &Args) -> Result<(), Error> {
+    let desc =
+        "Upload the code to the playground and reply with a shareable link, without running it";
+    generic_help(args, "shorten", desc, true, "code")
+}
+
+/// Compiler Explorer has no id for "the latest stable", so this has to be bumped from time to time
+const GODBOLT_STABLE_COMPILER_ID: &str = "r1900";
+
+#[derive(Debug, Serialize)]
+struct GodboltCompiler<'a> {
+    id: &'a str,
+    options: String,
+}
+
+#[derive(Debug, Serialize)]
+struct GodboltSession<'a> {
+    id: u32,
+    language: &'a str,
+    source: &'a str,
+    compilers: Vec<GodboltCompiler<'a>>,
+}
+
+#[derive(Debug, Serialize)]
+struct GodboltShortenerRequest<'a> {
+    sessions: Vec<GodboltSession<'a>>,
+}
+
+#[derive(Debug, Deserialize)]
+struct GodboltShortenerResponse {
+    url: String,
+}
+
+fn post_godbolt_shortener(args: &Args, code: &str, flags: &CommandFlags) -> Result<String, Error> {
+    let compiler_id = match flags.channel {
+        Channel::Stable => GODBOLT_STABLE_COMPILER_ID,
+        Channel::Beta => "beta",
+        Channel::Nightly => "nightly",
+    };
+    let mut options = format!("--edition={}", flags.edition);
+    match (flags.opt_level, flags.mode) {
+        (Some(opt_level), _) => options += &format!(" -Copt-level={}", opt_level),
+        (None, Mode::Release) => options += " -Copt-level=3",
+        (None, Mode::Debug) => {}
+    }
+    if let PanicStrategy::Abort = flags.panic {
+        options += " -Cpanic=abort";
+    }
+
+    let request = GodboltShortenerRequest {
+        sessions: vec![GodboltSession {
+            id: 1,
+            language: "rust",
+            source: code,
+            compilers: vec![GodboltCompiler {
+                id: compiler_id,
+                options,
+            }],
+        }],
+    };
+
+    let response: GodboltShortenerResponse = args
+        .http
+        .post("https://godbolt.org/api/shortener")
+        .header(header::ACCEPT, "application/json")
+        .json(&request)
+        .send()?
+        .error_for_status()?
+        .json()?;
+    Ok(response.url)
+}
+
+pub fn godbolt_link(args: &Args) -> Result<(), Error> {
+    let code = crate::extract_code(args.body)?;
+    let (flags, flag_parse_errors) = parse_flags(args, &code);
+
+    let reply = match post_godbolt_shortener(args, &code, &flags) {
+        Ok(url) => url,
+        Err(e) => {
+            warn!("Couldn't create Compiler Explorer link: {}", e);
+            "Compiler Explorer didn't create a link, but you can paste your code into \
+            https://rust.godbolt.org yourself"
+                .to_owned()
+        }
+    };
+    api::send_reply(args, &format!("{}{}", flag_parse_errors, reply))
+}
+
+pub fn godbolt_link_help(args: &Args) -> Result<(), Error> {
+    let desc = "Upload the code to Compiler Explorer (https://rust.godbolt.org) and reply with a \
+        link, for when you want to dig deeper into the assembly than ?godbolt allows. The \
+        channel, edition, mode, opt_level and panic flags select the compiler and its options";
+    generic_help(args, "godboltlink", desc, true, "code")
+}
+
+/// How many lines of each text [`line_diff`] compares. The table of the longest common
+/// subsequence has as many entries as the product of both line counts, and the texts can be
+/// untrusted program output, so they have to be limited. A reply can't show much more anyway
+const MAX_DIFF_LINES: usize = 1000;
+
+/// Compute a line-based diff of two texts using their longest common subsequence. Like in
+/// `diff -u`, unchanged lines are prefixed with a space, removed lines with `-` and added lines
+/// with `+`. Only the first [`MAX_DIFF_LINES`] lines of each text are compared
+///
+/// ```rust
+/// assert_eq!(line_diff("a\nb\nc", "a\nc\nd"), " a\n-b\n c\n+d\n");
+///
+/// let long = "x\n".repeat(50_000);
+/// let diff = line_diff(&long, &long);
+/// assert_eq!(diff.lines().count(), MAX_DIFF_LINES + 1);
+/// assert!(diff.ends_with("(only the first 1000 lines were compared)\n"));
+/// ```
+fn line_diff(a: &str, b: &str) -> String {
+    let a = a.lines().take(MAX_DIFF_LINES + 1).collect::<Vec<_>>();
+    let b = b.lines().take(MAX_DIFF_LINES + 1).collect::<Vec<_>>();
+    let is_truncated = a.len() > MAX_DIFF_LINES || b.len() > MAX_DIFF_LINES;
+    let a = &a[..a.len().min(MAX_DIFF_LINES)];
+    let b = &b[..b.len().min(MAX_DIFF_LINES)];
+
+    // lcs[i][j] is the length of the longest common subsequence of a[i..] and b[j..]
+    let mut lcs = vec![vec![0; b.len() + 1]; a.len() + 1];
+    for i in (0..a.len()).rev() {
+        for j in (0..b.len()).rev() {
+            lcs[i][j] = if a[i] == b[j] {
+                lcs[i + 1][j + 1] + 1
+            } else {
+                lcs[i + 1][j].max(lcs[i][j + 1])
+            };
+        }
+    }
+
+    let mut diff = String::new();
+    let (mut i, mut j) = (0, 0);
+    while i < a.len() || j < b.len() {
+        if i < a.len() && j < b.len() && a[i] == b[j] {
+            diff += &format!(" {}\n", a[i]);
+            i += 1;
+            j += 1;
+        } else if i < a.len() && (j == b.len() || lcs[i + 1][j] >= lcs[i][j + 1]) {
+            diff += &format!("-{}\n", a[i]);
+            i += 1;
+        } else {
+            diff += &format!("+{}\n", b[j]);
+            j += 1;
+        }
+    }
+    if is_truncated {
+        diff += &format!("(only the first {} lines were compared)\n", MAX_DIFF_LINES);
+    }
+    diff
+}
+
+pub fn diff(args: &Args) -> Result<(), Error> {
+    let code = extract_code_or_gist(args)?;
+    let (flags, mut flag_parse_errors) = parse_flags(args, &code);
+    let code = &maybe_wrap(&code, ResultHandling::None);
+
+    let mut parse_channel = |param, default| match args.params.get(param).map(|c| c.parse()) {
+        Some(Ok(channel)) => channel,
+        Some(Err(e)) => {
+            flag_parse_errors += &format!("{}\n", e);
+            default
+        }
+        None => default,
+    };
+    let from: Channel = parse_channel("from", Channel::Stable);
+    let to: Channel = parse_channel("to", Channel::Nightly);
+
+    let run = |channel| -> Result<String, Error> {
+        let result: PlayResult = cached_post(
+            args,
+            &flags,
+            "https://play.rust-lang.org/execute",
+            &PlaygroundRequest {
+                code,
+                channel,
+                crate_type: crate_type(&flags, code),
+                edition: flags.edition,
+                mode: flags.mode,
+                tests: false,
+                backtrace: flags.backtrace,
+            },
+        )?;
+
+        let output = format_play_eval_stderr(result);
+        Ok(join_sections(
+            &join_sections(&output.compiler_warnings, &output.program_stderr),
+            &output.stdout,
+        ))
+    };
+    let from_output = run(from)?;
+    let to_output = run(to)?;
+
+    if from_output == to_output {
+        return api::send_reply(
+            args,
+            &format!(
+                "{}No difference between {} and {}",
+                flag_parse_errors, from, to
+            ),
+        );
+    }
+
+    let text_body = format!(
+        "{}```diff\n--- {}\n+++ {}\n{}",
+        flag_parse_errors,
+        from,
+        to,
+        line_diff(&from_output, &to_output)
+    );
+    crate::reply_paginated_long_text(
+        args,
+        &text_body,
+        "```",
+        &output_too_large_message(args, &flags, code, &text_body)?,
+    )
+}
+
+pub fn diff_help(args: &Args) -> Result<(), Error> {
+    let desc = "Run code on two release channels and show how the outputs differ. The channels \
+        can be chosen with from= and to= (default: from=stable to=nightly)";
+    generic_help(args, "diff", desc, false, "code")
+}
+
+/// The edition to format with, from the `fmt_edition=` param of ?fmt and ?expand. Defaults to
+/// the edition the code is compiled with
+fn rustfmt_edition(args: &Args, flags: &CommandFlags, errors: &mut String) -> Edition {
+    match args.params.get("fmt_edition").map(|e| e.parse()) {
+        Some(Ok(edition)) => edition,
+        Some(Err(e)) => {
+            *errors += &format!("{}\n", e);
+            flags.edition
+        }
+        None => flags.edition,
+    }
+}
+
+/// Translate the Miri flags from the `flags=` param, e.g. `flags=-Zmiri-tree-borrows`. The
+/// playground doesn't pass arbitrary flags to Miri, so only the flags that correspond to a
+/// playground setting are supported, the others are reported in `errors`
+fn miri_aliasing_model(args: &Args, errors: &mut String) -> AliasingModel {
+    let mut aliasing_model = AliasingModel::Stacked;
+    for flag in args
+        .params
+        .get("flags")
+        .into_iter()
+        .flat_map(|f| f.split(','))
+    {
+        match flag {
+            "-Zmiri-tree-borrows" => aliasing_model = AliasingModel::Tree,
+            _ => {
+                *errors += &format!(
+                    "Miri flag `{}` is not supported by the playground, only \
+                    -Zmiri-tree-borrows is\n",
+                    flag
+                )
+            }
+        }
+    }
+    aliasing_model
+}
+
+/// A one-line summary of Miri's verdict, to go above its full report. None if the code didn't
+/// compile, so Miri didn't get to run it
+///
+/// ```rust
+/// let stderr = "Running `/playground/.rustup/toolchains/nightly/bin/cargo-miri runner`
+/// error: Undefined Behavior: out-of-bounds pointer use: alloc1 has size 4, so pointer to 4 bytes \
+/// starting at offset 4 is out-of-bounds
+///  --> src/main.rs:3:14
+/// note: inside `main` at src/main.rs:3:14";
+/// assert_eq!(
+///     miri_summary(stderr, false).as_deref(),
+///     Some(
+///         "⚠ Miri detected undefined behavior: out-of-bounds pointer use: alloc1 has size 4, so \
+///         pointer to 4 bytes starting at offset 4 is out-of-bounds"
+///     ),
+/// );
+///
+/// let stderr = "Running `cargo-miri runner`\nerror: unsupported operation: can't call foreign \
+///     function `socket` on OS `linux`";
+/// assert_eq!(
+///     miri_summary(stderr, false).as_deref(),
+///     Some("⚠ Miri doesn't support an operation of the program, so it couldn't check all of it"),
+/// );
+///
+/// let stderr = "Running `cargo-miri runner`\nerror: memory leaked: alloc1 (Rust heap, size: 4)";
+/// assert_eq!(miri_summary(stderr, false).as_deref(), Some("⚠ Miri detected a memory leak"));
+///
+/// let stderr = "Running `cargo-miri runner`";
+/// assert_eq!(
+///     miri_summary(stderr, true).as_deref(),
+///     Some("No undefined behavior detected by Miri"),
+/// );
+///
+/// assert_eq!(miri_summary("error[E0425]: cannot find value `x`", false), None);
+/// ```
+fn miri_summary(stderr: &str, success: bool) -> Option<String> {
+    if !stderr.contains("Running `") {
+        return None;
+    }
+
+    const UB_MARKER: &str = "Undefined Behavior: ";
+    if let Some(ub_start) = stderr.find(UB_MARKER) {
+        let description = stderr[(ub_start + UB_MARKER.len())..].lines().next()?;
+        return Some(format!(
+            "⚠ Miri detected undefined behavior: {}",
+            description.trim()
+        ));
+    }
+
+    Some(
+        if stderr.contains("error: unsupported operation") {
+            "⚠ Miri doesn't support an operation of the program, so it couldn't check all of it"
+        } else if stderr.contains("error: memory leaked") {
+            "⚠ Miri detected a memory leak"
+        } else if success {
+            "No undefined behavior detected by Miri"
+        } else {
+            "No undefined behavior detected by Miri, but the program failed"
+        }
+        .to_owned(),
+    )
+}
+
+pub fn miri(args: &Args) -> Result<(), Error> {
+    let code = crate::extract_code(args.body)?;
+    let (flags, mut flag_parse_errors) = parse_flags(args, &code);
+    let (code, line_map) = wrap_code(&code, ResultHandling::Discard);
+    let code = &code;
+    let aliasing_model = miri_aliasing_model(args, &mut flag_parse_errors);
+
+    let result: PlayResult = cached_post(
+        args,
+        &flags,
+        "https://play.rust-lang.org/miri",
+        &MiriRequest {
+            code,
+            edition: flags.edition,
+            crate_type: crate_type(&flags, code),
+            aliasing_model,
+        },
+    )?;
+
+    let stderr = strip_ansi(&result.stderr);
+    if let Some(summary) = miri_summary(&stderr, result.success) {
+        flag_parse_errors += &format!("{}\n", summary);
+    }
+
+    // Miri's reports are output of the interpreted program, not compiler diagnostics
+    let result = PlayOutput {
+        success: result.success,
+        compiler_warnings: String::new(),
+        program_stderr: line_map.map_diagnostics(extract_relevant_lines(
+            &stderr,
+            &["Running `/playground"],
+            &["error: aborting"],
+        )),
+        stdout: strip_ansi(&result.stdout),
+        warning_count: 0,
+    };
+
+    react_result_status(args, result.success);
+    send_reply(args, result, code, &flags, &flag_parse_errors)
+}
+
+pub fn miri_help(args: &Args) -> Result<(), Error> {
+    let desc = "Execute this program in the Miri interpreter to detect certain cases of undefined behavior (like out-of-bounds memory access). \
+        Miri flags can be passed with flags=, but the playground only supports `flags=-Zmiri-tree-borrows`. \
+        Isolation can't be disabled, so the program has no access to the clock, randomness or the file system";
+    generic_help(args, "miri", desc, false, "code")
+}
+
+/// Keep only the top-level items of rustfmt'd code whose first line mentions `name`, together
+/// with their attributes. This way, `Foo` selects the struct `Foo` and the impls derived for it.
+/// Returns `None` if there's no such item
+///
+/// ```rust
+/// let code = "#[derive(Clone)]\nstruct Foo;\n#[automatically_derived]\nimpl Clone for Foo {\n    \
+///     fn clone(&self) -> Foo {\n        Foo\n    }\n}\nfn main() {\n    let _ = Foo;\n}\n";
+/// assert_eq!(
+///     filter_items(code, "Foo").unwrap(),
+///     "#[derive(Clone)]\nstruct Foo;\n#[automatically_derived]\nimpl Clone for Foo {\n    \
+///     fn clone(&self) -> Foo {\n        Foo\n    }\n}\n",
+/// );
+/// assert_eq!(filter_items(code, "main").unwrap(), "fn main() {\n    let _ = Foo;\n}\n");
+/// assert_eq!(filter_items(code, "Fo"), None);
+/// ```
+fn filter_items(code: &str, name: &str) -> Option<String> {
+    let mentions_name = |line: &str| {
+        line.split(|c: char| !(c.is_alphanumeric() || c == '_'))
+            .any(|word| word == name)
+    };
+
+    let mut output = String::new();
+    let mut item = String::new();
+    let mut is_match = None;
+    for line in code.lines() {
+        item += line;
+        item += "\n";
+
+        let is_top_level = !line.starts_with(char::is_whitespace);
+        if is_top_level && is_match.is_none() && !line.starts_with('#') {
+            is_match = Some(mentions_name(line));
+        }
+        // Items end with their closing brace or semicolon, unindented in rustfmt'd code
+        if is_top_level && (line.starts_with('}') || line.ends_with(';') || line.ends_with('}')) {
+            if is_match == Some(true) {
+                output += &item;
+            }
+            item.clear();
+            is_match = None;
+        }
+    }
+
+    match output.is_empty() {
+        true => None,
+        false => Some(output),
+    }
+}
+
+pub fn expand_macros(args: &Args) -> Result<(), Error> {
+    let code = crate::extract_code(args.body)?;
+    let (flags, mut flag_parse_errors) = parse_flags(args, &code);
+    let code = maybe_wrap(&code, ResultHandling::None);
+    let was_fn_main_wrapped = matches!(code, Cow::Owned(_));
+
+    let mut result: PlayResult = cached_post(
+        args,
+        &flags,
+        "https://play.rust-lang.org/macro-expansion",
+        &MacroExpansionRequest {
+            code: &code,
+            edition: flags.edition,
+        },
+    )?;
+
+    result.stderr = extract_relevant_lines(
+        &result.stderr,
+        &["Finished ", "Compiling playground"],
+        &["error: aborting"],
+    )
+    .to_owned();
+
+    let fmt_edition = rustfmt_edition(args, &flags, &mut flag_parse_errors);
+    if result.success {
+        match apply_rustfmt(args, &result.stdout, fmt_edition, "") {
+            Ok(PlayResult { success: true, stdout, .. }) => result.stdout = stdout,
+            Ok(PlayResult { success: false, stderr, .. }) => warn!("Huh, rustfmt failed even though this code successfully passed through macro expansion before: {}", stderr),
+            Err(e) => warn!("Couldn't run rustfmt: {}", e),
+        }
+    }
+    if was_fn_main_wrapped {
+        result.stdout = strip_fn_main_boilerplate_from_formatted(&result.stdout);
+    }
+
+    if let (true, Some(item)) = (result.success, args.params.get("item")) {
+        match filter_items(&result.stdout, item) {
+            Some(items) => result.stdout = items,
+            None => {
+                flag_parse_errors += &format!(
+                    "No item mentioning `{}` found, showing the full expansion\n",
+                    item
+                )
+            }
+        }
+    }
+
+    send_reply(args, result.into(), &code, &flags, &flag_parse_errors)
+}
+
+pub fn expand_macros_help(args: &Args) -> Result<(), Error> {
+    let desc = "Expand macros to their raw desugared form. To only see the part you're \
+        interested in, pass item= with the name of an item, for example `item=Foo` shows `Foo` \
+        and the trait impls derived for it. The expanded code is formatted with the edition given by \
+        fmt_edition=, or else the one it's compiled with";
+    generic_help(args, "expand", desc, false, "code")
+}
+
+/// Build crate-level lint attributes from the `allow=`, `warn=` and `deny=` params, e.g.
+/// `warn=clippy::pedantic,clippy::nursery` becomes
+/// `#![warn(clippy::pedantic)]\n#![warn(clippy::nursery)]\n`. Invalid lint names are reported in
+/// `errors`
+fn lint_level_attributes(args: &Args, errors: &mut String) -> String {
+    let mut attributes = String::new();
+    for &level in &["allow", "warn", "deny"] {
+        let lints = match args.params.get(level) {
+            Some(x) => x,
+            None => continue,
+        };
+        for lint in lints.split(',').filter(|lint| !lint.is_empty()) {
+            // don't let people inject arbitrary code through the attribute
+            if lint
+                .chars()
+                .all(|c| c.is_alphanumeric() || c == '_' || c == ':' || c == '-')
+            {
+                attributes += &format!("#![{}({})]\n", level, lint);
+            } else {
+                *errors += &format!("invalid lint name `{}`\n", lint);
+            }
+        }
+    }
+    attributes
+}
+
+pub fn clippy(args: &Args) -> Result<(), Error> {
+    let user_code = crate::extract_code(args.body)?;
+    let (flags, mut flag_parse_errors) = parse_flags(args, &user_code);
+
+    // The lint attributes are put in front of the user code, so that they stay at the top of the
+    // crate when maybe_wrap hoists the crate attributes. Inline lint attributes by the user come
+    // later and therefore take precedence
+    let lint_attributes = lint_level_attributes(args, &mut flag_parse_errors);
+    let code = lint_attributes.clone() + &user_code;
+    let (code, mut line_map) = wrap_code(&code, ResultHandling::Discard);
+    line_map.skip_prepended_lines(lint_attributes.lines().count());
+    let code = &code;
+
+    let mut result = run_clippy(args, &flags, code)?;
+    // The suggestions refer to the lines of the sent code, so they're applied before mapping
+    let fixed_code = match flags.fix {
+        true => apply_suggestions(code, &result.compiler_warnings),
+        false => None,
+    };
+    result.compiler_warnings = line_map.map_diagnostics(&result.compiler_warnings);
+    react_result_status(args, result.success);
+    send_reply(args, result, code, &flags, &flag_parse_errors)?;
+
+    if flags.fix {
+        let reply = match fixed_code {
+            Some(fixed_code) => format!("Suggested fix:\n```rust\n{}```", fixed_code),
+            None => "None of the suggestions could be applied automatically".to_owned(),
+        };
+        if reply.chars().count() > crate::DISCORD_MESSAGE_LIMIT {
+            args.msg
+                .channel_id
+                .say(args.cx, "The fixed code is too long to show")?;
+        } else {
+            args.msg.channel_id.say(args.cx, reply)?;
+        }
+    }
+    Ok(())
+}
+
+/// Run the code through the playground's clippy endpoint and strip the cargo noise from the output
+fn run_clippy(args: &Args, flags: &CommandFlags, code: &str) -> Result<PlayOutput, Error> {
+    let mut result: PlayResult = cached_post(
+        args,
+        flags,
+        "https://play.rust-lang.org/clippy",
+        &ClippyRequest {
+            code,
+            edition: flags.edition,
+            crate_type: crate_type(flags, code),
+        },
+    )?;
+
+    let stderr = strip_ansi(&result.stderr);
+    let warning_count = count_warnings(&stderr);
+    result.stderr = extract_relevant_lines(
+        &stderr,
+        &["Checking playground", "Running `/playground"],
+        &[
+            "error: aborting",
+            "1 warning emitted",
+            "warnings emitted",
+            "Finished ",
+        ],
+    )
+    .to_owned();
+
+    Ok(PlayOutput {
+        warning_count,
+        ..result.into()
+    })
+}
+
+/// Split a source line of a diagnostic like `3 |     let x = 1;` into the line number, the
+/// gutter marker (`|`, or `+`, `-` and `~` in suggestions) and the code
+fn parse_diagnostic_source_line(line: &str) -> Option<(usize, char, &str)> {
+    let line = line.trim_start();
+    let number_end = line.find(|c: char| !c.is_ascii_digit())?;
+    let number = line[..number_end].parse().ok()?;
+    let rest = line[number_end..].strip_prefix(' ')?;
+    let marker = rest.chars().next().filter(|c| "|+-~".contains(*c))?;
+    let code = &rest[1..];
+    Some((number, marker, code.strip_prefix(' ').unwrap_or(code)))
+}
+
+/// Apply the code suggestions from the compiler's human-readable output to the code, on a
+/// best-effort basis. Two kinds of suggestions are understood: inline ones, where the replacement
+/// is quoted after the `^^^` markers, and ones shown as separate snippet after a `help:` line,
+/// as long as they replace or remove lines one by one. Other suggestions, and suggestions for
+/// lines that don't match the code or were already changed, are skipped. Returns `None` if no
+/// suggestion could be applied
+///
+/// ```rust
+/// let code = "fn main() {\n    let x = vec![1];\n    if x.len() == 0 {}\n}\n";
+/// let stderr = "warning: length comparison to zero
+///  --> src/main.rs:3:8
+///   |
+/// 3 |     if x.len() == 0 {}
+///   |        ^^^^^^^^^^^^ help: using `is_empty` is clearer: `x.is_empty()`
+/// ";
+/// assert_eq!(
+///     apply_suggestions(code, stderr).unwrap(),
+///     "fn main() {\n    let x = vec![1];\n    if x.is_empty() {}\n}\n",
+/// );
+///
+/// let code = "fn main() {\n    let x = 5;\n    let y = x.clone();\n}\n";
+/// let stderr = "warning: using `clone` on type `i32` which implements the `Copy` trait
+///  --> src/main.rs:3:13
+///   |
+/// 3 |     let y = x.clone();
+///   |             ^^^^^^^^^
+///   |
+/// help: try removing the `clone` call
+///   |
+/// 3 -     let y = x.clone();
+/// 3 +     let y = x;
+///   |
+/// ";
+/// assert_eq!(
+///     apply_suggestions(code, stderr).unwrap(),
+///     "fn main() {\n    let x = 5;\n    let y = x;\n}\n",
+/// );
+///
+/// // suggestions that add lines would shift the line numbers, so they're skipped
+/// let stderr = "help: add a line
+///   |
+/// 2 -     let x = 5;
+/// 2 +     let x = 5;
+/// 3 +     let z = 6;
+///   |
+/// ";
+/// assert_eq!(apply_suggestions(code, stderr), None);
+/// ```
+fn apply_suggestions(code: &str, stderr: &str) -> Option<String> {
+    let mut lines = code
+        .lines()
+        .map(|line| Some(line.to_owned()))
+        .collect::<Vec<_>>();
+    let mut changed_lines = Vec::new();
+    let stderr_lines = stderr.lines().collect::<Vec<_>>();
+
+    for (i, stderr_line) in stderr_lines.iter().enumerate() {
+        let next_line = stderr_lines.get(i + 1).copied().unwrap_or("");
+
+        // Inline suggestion: a source line, followed by markers and the quoted replacement
+        if let Some((number, '|', source)) = parse_diagnostic_source_line(stderr_line) {
+            let markers = match next_line.trim_start().strip_prefix('|') {
+                Some(markers) => markers.strip_prefix(' ').unwrap_or(markers),
+                None => continue,
+            };
+            let span_start = match markers.find('^') {
+                Some(x) => x,
+                None => continue,
+            };
+            let span_length = markers[span_start..]
+                .find(|c| c != '^')
+                .unwrap_or(markers.len() - span_start);
+            let help = &markers[(span_start + span_length)..];
+            let replacement = match (help.trim_start().starts_with("help:"), help.rfind(": `")) {
+                (true, Some(x)) => match help[(x + 3)..].strip_suffix('`') {
+                    Some(replacement) => replacement,
+                    None => continue,
+                },
+                _ => continue,
+            };
+
+            let line = match lines.get(number.wrapping_sub(1)) {
+                // the columns are only byte offsets for ASCII
+                Some(Some(line)) if line == source && line.is_ascii() => line,
+                _ => continue,
+            };
+            if changed_lines.contains(&number) || line.len() < span_start + span_length {
+                continue;
+            }
+            let fixed_line = format!(
+                "{}{}{}",
+                &line[..span_start],
+                replacement,
+                &line[(span_start + span_length)..]
+            );
+            lines[number - 1] = Some(fixed_line);
+            changed_lines.push(number);
+            continue;
+        }
+
+        // Separate suggestion snippet, after a `help:` line
+        if !(stderr_line.starts_with("help:") && next_line.trim() == "|") {
+            continue;
+        }
+        let mut removed = Vec::new();
+        let mut replaced = Vec::new();
+        for snippet_line in &stderr_lines[(i + 2)..] {
+            match parse_diagnostic_source_line(snippet_line) {
+                Some((number, '-', source)) => removed.push((number, source)),
+                Some((number, _, source)) => replaced.push((number, source)),
+                None if snippet_line.trim() == "|" => {}
+                None => break,
+            }
+        }
+
+        // Adding or removing some of the lines would shift the line numbers after them
+        let is_applicable =
+            (removed.is_empty() || replaced.is_empty() || removed.len() == replaced.len())
+                && removed.iter().all(|(number, source)| {
+                    lines.get(number.wrapping_sub(1)) == Some(&Some((*source).to_owned()))
+                })
+                && removed.iter().chain(&replaced).all(|(number, _)| {
+                    (1..=lines.len()).contains(number) && !changed_lines.contains(number)
+                });
+        if !is_applicable || (removed.is_empty() && replaced.is_empty()) {
+            continue;
+        }
+        for (number, _) in &removed {
+            lines[number - 1] = None;
+        }
+        for (number, source) in &replaced {
+            lines[number - 1] = Some((*source).to_owned());
+        }
+        changed_lines.extend(removed.iter().chain(&replaced).map(|(number, _)| *number));
+    }
+
+    if changed_lines.is_empty() {
+        return None;
+    }
+    let mut fixed_code = String::new();
+    for line in lines.into_iter().flatten() {
+        fixed_code += &line;
+        fixed_code += "\n";
+    }
+    Some(fixed_code)
+}
+
+/// Typecheck the code without running it. Replies with the diagnostics, or `clean_message` if
+/// there are none. `crate_attributes` is put in front of the code like in [`play_or_eval`]
+fn check_code(
+    args: &Args,
+    crate_attributes: &str,
+    code: &str,
+    result_handling: ResultHandling,
+    flags: &CommandFlags,
+    flag_parse_errors: &str,
+    clean_message: &str,
+) -> Result<(), Error> {
+    // The playground can't `cargo check`, but clippy without its lints is the same thing
+    let code = format!("#![allow(clippy::all)]\n{}{}", crate_attributes, code);
+    let (code, mut line_map) = wrap_code(&code, result_handling);
+    line_map.skip_prepended_lines(1 + crate_attributes.lines().count());
+    let code = &code;
+
+    let mut result = run_clippy(args, flags, code)?;
+    result.compiler_warnings = line_map.map_diagnostics(&result.compiler_warnings);
+    if result.success && result.compiler_warnings.trim().is_empty() {
+        return api::send_reply(args, &format!("{}{}", flag_parse_errors, clean_message));
+    }
+    send_reply(args, result, code, flags, flag_parse_errors)
+}
+
+pub fn check(args: &Args) -> Result<(), Error> {
+    let code = crate::extract_code(args.body)?;
+    let (flags, flag_parse_errors) = parse_flags(args, &code);
+
+    check_code(
+        args,
+        "",
+        &code,
+        ResultHandling::Discard,
+        &flags,
+        &flag_parse_errors,
+        "Compiles cleanly",
+    )
+}
+
+pub fn check_help(args: &Args) -> Result<(), Error> {
+    let desc = "Check whether the code compiles and show the compiler diagnostics, without \
+        running it";
+    generic_help(args, "check", desc, false, "code")
+}
+
+/// Editions that `?whatcompiles` tries, one playground request each
+const WHAT_COMPILES_EDITIONS: [Edition; 3] = [Edition::E2015, Edition::E2018, Edition::E2021];
+
+/// Find the first compiler error in the stderr of a check request
+///
+/// ```rust
+/// let stderr = "    Checking playground v0.0.1 (/playground)
+/// error[E0425]: cannot find value `async` in this scope
+///  --> src/main.rs:2:13
+/// error: aborting due to previous error
+/// ";
+/// assert_eq!(
+///     first_compile_error(stderr),
+///     Some("error[E0425]: cannot find value `async` in this scope"),
+/// );
+///
+/// let stderr = "    Checking playground v0.0.1 (/playground)
+/// warning: unused variable: `x`
+///     Finished dev [unoptimized + debuginfo] target(s) in 0.50s
+/// ";
+/// assert_eq!(first_compile_error(stderr), None);
+/// ```
+fn first_compile_error(stderr: &str) -> Option<&str> {
+    stderr.lines().find(|line| line.starts_with("error"))
+}
+
+pub fn what_compiles(args: &Args) -> Result<(), Error> {
+    let code = crate::extract_code(args.body)?;
+    let (flags, flag_parse_errors) = parse_flags(args, &code);
+    // Like `check_code`, clippy without its lints only checks the code, it isn't even built
+    let code = format!(
+        "#![allow(clippy::all)]\n{}",
+        maybe_wrap(&code, ResultHandling::None)
+    );
+
+    // One request after the other, to not hammer the playground
+    let mut matrix = String::new();
+    for &edition in &WHAT_COMPILES_EDITIONS {
+        let result: PlayResult = cached_post(
+            args,
+            &flags,
+            "https://play.rust-lang.org/clippy",
+            &ClippyRequest {
+                code: &code,
+                edition,
+                crate_type: crate_type(&flags, &code),
+            },
+        )?;
+
+        let stderr = strip_ansi(&result.stderr);
+        matrix += &match first_compile_error(&stderr) {
+            _ if result.success => format!("{}: ✅\n", edition),
+            Some(error) => format!("{}: ❌ {}\n", edition, error),
+            None => format!("{}: ❌\n", edition),
+        };
+    }
+
+    api::send_reply(args, &format!("{}```\n{}```", flag_parse_errors, matrix))
+}
+
+pub fn what_compiles_help(args: &Args) -> Result<(), Error> {
+    let desc = "Check the code under each of the 2015, 2018 and 2021 editions without running it \
+        and show which of them it compiles under, along with the first error for the others. \
+        Useful for explaining edition-specific breakage";
+    generic_help(args, "whatcompiles", desc, false, "code")
+}
+
+pub fn clippy_help(args: &Args) -> Result<(), Error> {
+    let desc = "Catch common mistakes and improve the code using the Clippy linter. \
+        Lint levels can be set with allow=, warn= and deny=, for example \
+        `warn=clippy::pedantic,clippy::nursery`. With fix=true, the suggestions are applied on a \
+        best-effort basis and the fixed code is shown as well";
+    generic_help(args, "clippy", desc, false, "code")
+}
+
+pub fn fmt(args: &Args) -> Result<(), Error> {
+    let user_code = crate::extract_code(args.body)?;
+    let code = &maybe_wrap(&user_code, ResultHandling::None);
+    let was_fn_main_wrapped = matches!(code, Cow::Owned(_));
+    let (flags, mut flag_parse_errors) = parse_flags(args, &user_code);
+
+    let show_diff = match args.params.get("diff") {
+        Some(diff) => parse_bool("diff", diff).unwrap_or_else(|e| {
+            flag_parse_errors += &format!("{}\n", e);
+            false
+        }),
+        None => false,
     };
 
-    result.stderr = match (compiler_warnings, program_stderr) {
-        ("", "") => String::new(),
-        (warnings, "") => warnings.to_owned(),
-        ("", stderr) => stderr.to_owned(),
-        (warnings, stderr) => format!("{}\n{}", warnings, stderr),
+    let rustfmt_config = match args.params.get("config") {
+        Some(config) => parse_rustfmt_config(config, &mut flag_parse_errors),
+        None => String::new(),
     };
+
+    let fmt_edition = rustfmt_edition(args, &flags, &mut flag_parse_errors);
+    let mut result = apply_rustfmt(args, &code, fmt_edition, &rustfmt_config)?;
+    if was_fn_main_wrapped {
+        result.stdout = strip_fn_main_boilerplate_from_formatted(&result.stdout);
+    }
+
+    if !(show_diff && result.success) {
+        return send_reply(args, result.into(), code, &flags, &flag_parse_errors);
+    }
+
+    if result.stdout.trim_end() == user_code.trim_end() {
+        return api::send_reply(args, &format!("{}Already formatted", flag_parse_errors));
+    }
+    let text_body = format!(
+        "{}```diff\n{}",
+        flag_parse_errors,
+        line_diff(&user_code, &result.stdout)
+    );
+    crate::reply_paginated_long_text(
+        args,
+        &text_body,
+        "```",
+        &output_too_large_message(args, &flags, code, &text_body)?,
+    )
 }
 
-// ================================
-// ACTUAL BOT COMMANDS BEGIN HERE
-// ================================
+pub fn fmt_help(args: &Args) -> Result<(), Error> {
+    let desc = "Format code using rustfmt. With diff=true, only the changed lines are shown. \
+        rustfmt options can be set with config=, for example `config=max_width=80,tab_spaces=2`. \
+        fmt_edition= overrides the edition to format with, which defaults to edition=";
+    generic_help(args, "fmt", desc, false, "code")
+}
 
-// play and eval work similarly, so this function abstracts over the two
-fn play_or_eval(args: &Args, result_handling: ResultHandling) -> Result<(), Error> {
-    let code = maybe_wrap(crate::extract_code(args.body)?, result_handling);
-    let (flags, flag_parse_errors) = parse_flags(args);
+pub fn explain(args: &Args) -> Result<(), Error> {
+    let error_code = args.body.trim();
 
-    let mut result: PlayResult = args
-        .http
-        .post("https://play.rust-lang.org/execute")
-        .json(&PlaygroundRequest {
-            code: &code,
-            channel: flags.channel,
-            crate_type: if code.contains("fn main") {
-                CrateType::Binary
-            } else {
-                CrateType::Library
-            },
-            edition: flags.edition,
-            mode: flags.mode,
-            tests: false,
-        })
-        .send()?
-        .json()?;
+    // Validate before passing user input to a process
+    let is_valid_error_code = error_code.len() == 5
+        && error_code.starts_with('E')
+        && error_code[1..].chars().all(|c| c.is_ascii_digit());
+    if !is_valid_error_code {
+        return api::send_reply(
+            args,
+            &format!(
+                "`{}` is not a valid error code. Error codes look like `E0382`",
+                error_code
+            ),
+        );
+    }
 
-    format_play_eval_stderr(&mut result);
+    let output = std::process::Command::new("rustc")
+        .args(["--explain", error_code])
+        .output()?;
+    if !output.status.success() {
+        return api::send_reply(args, &format!("No explanation found for {}", error_code));
+    }
 
-    send_reply(args, result, &code, &flags, &flag_parse_errors)
+    crate::reply_potentially_long_text(
+        args,
+        &String::from_utf8_lossy(&output.stdout),
+        "",
+        &format!(
+            "\n\nRead the full explanation at https://doc.rust-lang.org/error_codes/{}.html",
+            error_code
+        ),
+    )
 }
 
-pub fn play(args: &Args) -> Result<(), Error> {
-    play_or_eval(args, ResultHandling::None)
+#[derive(Debug, Deserialize)]
+struct VersionInfo {
+    version: String,
+    hash: String,
+    date: String,
 }
 
-pub fn eval(args: &Args) -> Result<(), Error> {
-    play_or_eval(args, ResultHandling::Print)
+#[derive(Debug, Deserialize)]
+struct ChannelVersions {
+    rustc: VersionInfo,
 }
 
-pub fn play_and_eval_help(args: &Args, name: &str) -> Result<(), Error> {
-    generic_help(args, name, "Compile and run Rust code", true, "code")
+#[derive(Debug, Deserialize)]
+struct VersionsResponse {
+    stable: ChannelVersions,
+    beta: ChannelVersions,
+    nightly: ChannelVersions,
 }
 
-pub fn miri(args: &Args) -> Result<(), Error> {
-    let code = &maybe_wrap(crate::extract_code(args.body)?, ResultHandling::Discard);
-    let (flags, flag_parse_errors) = parse_flags(args);
+pub fn versions(args: &Args) -> Result<(), Error> {
+    let (flags, flag_parse_errors) = parse_flags(args, "");
 
-    let mut result: PlayResult = args
-        .http
-        .post("https://play.rust-lang.org/miri")
-        .json(&MiriRequest {
-            code,
-            edition: flags.edition,
-        })
-        .send()?
-        .json()?;
+    let url = "https://play.rust-lang.org/meta/versions";
+    let versions: VersionsResponse = cached_request(args, &flags, url.to_owned(), || {
+        args.http
+            .get(url)
+            .timeout(PLAYGROUND_TIMEOUT)
+            .send()
+            .map_err(timeout_error)
+    })?;
 
-    result.stderr = extract_relevant_lines(
-        &result.stderr,
-        &["Running `/playground"],
-        &["error: aborting"],
+    let mut reply = format!("{}```\n", flag_parse_errors);
+    for (channel, versions) in &[
+        ("stable", &versions.stable),
+        ("beta", &versions.beta),
+        ("nightly", &versions.nightly),
+    ] {
+        let rustc = &versions.rustc;
+        let short_hash = rustc.hash.get(..9).unwrap_or(&rustc.hash);
+        reply += &format!(
+            "{:<8} {} ({} {})\n",
+            format!("{}:", channel),
+            rustc.version,
+            short_hash,
+            rustc.date
+        );
+    }
+    reply += "```";
+
+    api::send_reply(args, &reply)
+}
+
+pub fn versions_help(args: &Args) -> Result<(), Error> {
+    api::send_reply(
+        args,
+        "Show the rustc versions of the stable, beta and nightly channels on the playground.
+```?versions```",
     )
-    .to_owned();
+}
 
-    send_reply(args, result, code, &flags, &flag_parse_errors)
+#[derive(Debug, Deserialize)]
+struct PlaygroundCrate {
+    name: String,
+    version: String,
 }
 
-pub fn miri_help(args: &Args) -> Result<(), Error> {
-    let desc = "Execute this program in the Miri interpreter to detect certain cases of undefined behavior (like out-of-bounds memory access)";
-    generic_help(args, "miri", desc, false, "code")
+#[derive(Debug, Deserialize)]
+struct CratesResponse {
+    crates: Vec<PlaygroundCrate>,
 }
 
-pub fn expand_macros(args: &Args) -> Result<(), Error> {
-    let code = maybe_wrap(crate::extract_code(args.body)?, ResultHandling::None);
-    let was_fn_main_wrapped = matches!(code, Cow::Owned(_));
-    let (flags, flag_parse_errors) = parse_flags(args);
+fn fetch_playground_crates(args: &Args, flags: &CommandFlags) -> Result<CratesResponse, Error> {
+    let url = "https://play.rust-lang.org/meta/crates";
+    cached_request(args, flags, url.to_owned(), || {
+        args.http
+            .get(url)
+            .timeout(PLAYGROUND_TIMEOUT)
+            .send()
+            .map_err(timeout_error)
+    })
+}
 
-    let mut result: PlayResult = args
-        .http
-        .post("https://play.rust-lang.org/macro-expansion")
-        .json(&MacroExpansionRequest {
-            code: &code,
-            edition: flags.edition,
-        })
-        .send()?
-        .json()?;
+/// The contents of a \`\`\`toml code block, which users can use to say which dependencies the
+/// code needs
+///
+/// ```rust
+/// let body = "```toml\n[dependencies]\nrand = \"0.8\"\n```\n```rust\nfn main() {}\n```";
+/// assert_eq!(manifest_block(body), Some("[dependencies]\nrand = \"0.8\""));
+/// assert_eq!(manifest_block("```rust\nfn main() {}\n```"), None);
+/// ```
+fn manifest_block(body: &str) -> Option<&str> {
+    let block_start = body.find("```toml")? + "```toml".len();
+    let block = body[block_start..]
+        .strip_prefix('\n')
+        .or_else(|| body[block_start..].strip_prefix("\r\n"))?;
+    let block_end = block.find("```")?;
+    Some(block[..block_end].trim())
+}
 
-    result.stderr = extract_relevant_lines(
-        &result.stderr,
-        &["Finished ", "Compiling playground"],
-        &["error: aborting"],
-    )
-    .to_owned();
+/// Parse the `[dependencies]` section of a manifest into the crate names and their version
+/// requirements, if given. Lines that can't be parsed are reported in `errors`
+///
+/// ```rust
+/// let manifest = "[package]\nname = \"foo\"\n\n[dependencies]\nrand = \"0.8\"\n# a comment\n\
+///     serde = { version = \"1.0\", features = [\"derive\"] }\nregex = { git = \"https://...\" }\n\
+///     oops\n[dev-dependencies]\nquickcheck = \"1\"";
+/// let mut errors = String::new();
+/// assert_eq!(
+///     manifest_dependencies(manifest, &mut errors),
+///     [("rand", Some("0.8")), ("serde", Some("1.0")), ("regex", None)],
+/// );
+/// assert_eq!(errors, "couldn't parse `oops` in the manifest\n");
+/// ```
+fn manifest_dependencies<'a>(
+    manifest: &'a str,
+    errors: &mut String,
+) -> Vec<(&'a str, Option<&'a str>)> {
+    /// The contents of a TOML string, like `"1.0"`
+    fn string_value(value: &str) -> Option<&str> {
+        value.trim().strip_prefix('"')?.split('"').next()
+    }
 
-    if result.success {
-        match apply_rustfmt(&result.stdout, flags.edition) {
-            Ok(PlayResult { success: true, stdout, .. }) => result.stdout = stdout,
-            Ok(PlayResult { success: false, stderr, .. }) => warn!("Huh, rustfmt failed even though this code successfully passed through macro expansion before: {}", stderr),
-            Err(e) => warn!("Couldn't run rustfmt: {}", e),
+    let mut dependencies = Vec::new();
+    let mut in_dependencies = false;
+    for line in manifest.lines().map(str::trim) {
+        if line.starts_with('[') {
+            in_dependencies = line == "[dependencies]";
+            continue;
         }
+        if !in_dependencies || line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+
+        let mut parts = line.splitn(2, '=');
+        let (name, value) = match (parts.next(), parts.next()) {
+            (Some(name), Some(value)) => (name.trim(), value.trim()),
+            _ => {
+                *errors += &format!("couldn't parse `{}` in the manifest\n", line);
+                continue;
+            }
+        };
+        let version = match value.strip_prefix('{') {
+            // inline table like `{ version = "1.0", features = ["derive"] }`
+            Some(table) => table.split(',').find_map(|entry| {
+                let mut parts = entry.splitn(2, '=');
+                match (parts.next(), parts.next()) {
+                    (Some(key), Some(value)) if key.trim() == "version" => string_value(value),
+                    _ => None,
+                }
+            }),
+            None => string_value(value),
+        };
+        dependencies.push((name, version));
     }
-    if was_fn_main_wrapped {
-        result.stdout = strip_fn_main_boilerplate_from_formatted(&result.stdout);
+    dependencies
+}
+
+/// Whether a crate version fulfills a version requirement like `0.8` or `^1.2.3`, in the way Cargo
+/// treats them by default. Requirements with other operators are assumed to be fulfilled
+///
+/// ```rust
+/// assert!(version_matches("0.8", "0.8.5"));
+/// assert!(version_matches("^0.8.3", "0.8.5"));
+/// assert!(version_matches("1", "1.4.0"));
+/// assert!(!version_matches("0.7", "0.8.5"));
+/// assert!(!version_matches("1.5", "1.4.0"));
+/// assert!(!version_matches("2", "1.4.0"));
+/// assert!(version_matches(">=0.5", "0.8.5"));
+/// ```
+fn version_matches(requirement: &str, version: &str) -> bool {
+    fn components(version: &str) -> Option<Vec<u64>> {
+        version.split('.').map(|c| c.parse().ok()).collect()
     }
 
-    send_reply(args, result, &code, &flags, &flag_parse_errors)
+    let requirement = requirement.trim().trim_start_matches('^');
+    let (required, available) = match (components(requirement), components(version)) {
+        (Some(required), Some(available)) => (required, available),
+        _ => return true,
+    };
+
+    // Like Cargo, everything up to the first non-zero component must be equal
+    let compatible_length = required
+        .iter()
+        .position(|&c| c != 0)
+        .map_or(required.len(), |i| i + 1);
+    required[..compatible_length] == available[..compatible_length.min(available.len())]
+        && available >= required
 }
+/// The playground has no way to pass a manifest, so the dependencies given in a \`\`\`toml block
+/// are only checked against the playground's crates. Unavailable crates and versions are reported
+/// in `errors`, to explain the unresolved imports that follow
+fn check_manifest(args: &Args, flags: &CommandFlags, errors: &mut String) -> Result<(), Error> {
+    let manifest = match manifest_block(args.body) {
+        Some(x) => x,
+        None => return Ok(()),
+    };
+    let dependencies = manifest_dependencies(manifest, errors);
+    if dependencies.is_empty() {
+        return Ok(());
+    }
 
-pub fn expand_macros_help(args: &Args) -> Result<(), Error> {
-    let desc = "Expand macros to their raw desugared form";
-    generic_help(args, "expand", desc, false, "code")
+    let crates = fetch_playground_crates(args, flags)?;
+    for (name, requirement) in dependencies {
+        let available = crates
+            .crates
+            .iter()
+            .find(|crate_| crate_.name.replace('-', "_") == name.replace('-', "_"));
+        match (available, requirement) {
+            (None, _) => {
+                *errors += &format!(
+                    "`{}` isn't available on the playground, see ?crates for the crates that are\n",
+                    name
+                )
+            }
+            (Some(crate_), Some(requirement)) if !version_matches(requirement, &crate_.version) => {
+                *errors += &format!(
+                    "The playground has {} {}, which doesn't match the requested version {}\n",
+                    crate_.name, crate_.version, requirement
+                )
+            }
+            _ => {}
+        }
+    }
+    Ok(())
 }
 
-pub fn clippy(args: &Args) -> Result<(), Error> {
-    let code = &maybe_wrap(crate::extract_code(args.body)?, ResultHandling::Discard);
-    let (flags, flag_parse_errors) = parse_flags(args);
+pub fn crates(args: &Args) -> Result<(), Error> {
+    let (flags, flag_parse_errors) = parse_flags(args, "");
+    let query = args.body.trim().to_lowercase();
 
-    let mut result: PlayResult = args
-        .http
-        .post("https://play.rust-lang.org/clippy")
-        .json(&ClippyRequest {
-            code,
-            edition: flags.edition,
-            crate_type: if code.contains("fn main") {
-                CrateType::Binary
-            } else {
-                CrateType::Library
-            },
-        })
-        .send()?
-        .json()?;
+    let crates = fetch_playground_crates(args, &flags)?;
 
-    result.stderr = extract_relevant_lines(
-        &result.stderr,
-        &["Checking playground", "Running `/playground"],
-        &[
-            "error: aborting",
-            "1 warning emitted",
-            "warnings emitted",
-            "Finished ",
-        ],
-    )
-    .to_owned();
+    let matching_crates = crates
+        .crates
+        .iter()
+        .filter(|crate_| crate_.name.to_lowercase().contains(&query))
+        .collect::<Vec<_>>();
+    if matching_crates.is_empty() {
+        return api::send_reply(
+            args,
+            &format!("{}No crates matching `{}`", flag_parse_errors, query),
+        );
+    }
 
-    send_reply(args, result, code, &flags, &flag_parse_errors)
+    let mut text_body = format!("{}```\n", flag_parse_errors);
+    for crate_ in matching_crates {
+        text_body += &format!("{} {}\n", crate_.name, crate_.version);
+    }
+
+    crate::reply_paginated_long_text(
+        args,
+        &text_body,
+        "```",
+        "Note: the list was truncated, search for a crate name to narrow it down",
+    )
 }
 
-pub fn clippy_help(args: &Args) -> Result<(), Error> {
-    let desc = "Catch common mistakes and improve the code using the Clippy linter";
-    generic_help(args, "clippy", desc, false, "code")
+pub fn crates_help(args: &Args) -> Result<(), Error> {
+    api::send_reply(
+        args,
+        "List the crates available on the playground, optionally only those whose name contains \
+        the search term. These can be used in code without a manifest, a \\`\\`\\`toml block with \
+        [dependencies] is only checked against them.
+```?crates [search term]```",
+    )
 }
 
-pub fn fmt(args: &Args) -> Result<(), Error> {
-    let code = &maybe_wrap(crate::extract_code(args.body)?, ResultHandling::None);
-    let was_fn_main_wrapped = matches!(code, Cow::Owned(_));
-    let (flags, flag_parse_errors) = parse_flags(args);
+pub fn explain_help(args: &Args) -> Result<(), Error> {
+    api::send_reply(
+        args,
+        "Show the detailed explanation of a compiler error code, as given by `rustc --explain`.
+```?explain E0382```",
+    )
+}
 
-    let mut result = apply_rustfmt(&code, flags.edition)?;
-    if was_fn_main_wrapped {
-        result.stdout = strip_fn_main_boilerplate_from_formatted(&result.stdout);
+/// Returns the byte indices of the `fn` keywords of all top-level functions. Functions nested in
+/// other functions or impl blocks, and `fn` inside comments and string literals are ignored
+///
+/// ```rust
+/// let code = "fn a() { fn nested() {} }\n// fn comment() {}\npub fn b() { \"fn c() {}\"; }";
+/// assert_eq!(top_level_fn_indices(code), [0, 49]);
+/// assert_eq!(top_level_fn_indices("impl X { fn method() {} }"), []);
+/// assert_eq!(top_level_fn_indices("const fn_count: u32 = 0;"), []);
+/// ```
+fn top_level_fn_indices(code: &str) -> Vec<usize> {
+    let mut indices = Vec::new();
+    let mut depth = 0;
+    let mut previous_char = ' ';
+    let mut chars = code.char_indices();
+    while let Some((i, c)) = chars.next() {
+        match c {
+            '{' => depth += 1,
+            '}' => depth -= 1,
+            '"' => {
+                // skip over the string literal
+                let mut escaped = false;
+                for (_, c) in &mut chars {
+                    match c {
+                        _ if escaped => escaped = false,
+                        '\\' => escaped = true,
+                        '"' => break,
+                        _ => {}
+                    }
+                }
+            }
+            '/' if code[i..].starts_with("//") => {
+                // skip over the comment
+                for (_, c) in &mut chars {
+                    if c == '\n' {
+                        break;
+                    }
+                }
+            }
+            'f' if depth == 0
+                && code[i..].starts_with("fn ")
+                && !(previous_char.is_alphanumeric() || previous_char == '_') =>
+            {
+                indices.push(i)
+            }
+            _ => {}
+        }
+        previous_char = c;
     }
+    indices
+}
 
-    send_reply(args, result, code, &flags, &flag_parse_errors)
+/// Find the functions to benchmark, which are all top-level functions, `pub` or not. The harness
+/// calls them as `fn()`, so `unsafe` and `async` functions and functions with generics, parameters
+/// or a return value are skipped.
+/// Returns the names of the functions to benchmark and a message for each skipped function
+///
+/// ```rust
+/// let code = "pub fn a() {}\nfn b(x: u32) {}\nfn c() -> u32 { 0 }\npub fn d() -> () {}";
+/// let (names, skipped) = find_bench_functions(code);
+/// assert_eq!(names, ["a", "d"]);
+/// assert_eq!(
+///     skipped,
+///     "Skipped `b`, because it has parameters\nSkipped `c`, because it returns a value\n",
+/// );
+///
+/// let (names, skipped) = find_bench_functions("pub fn e<T>() {}\nfn f() { (0..3).for_each(|_| {}) }");
+/// assert_eq!(names, ["f"]);
+/// assert_eq!(skipped, "Skipped `e<T>`, because it's generic\n");
+///
+/// let (names, skipped) = find_bench_functions("pub unsafe fn g() {}\nasync fn h() {}\nconst fn i() {}");
+/// assert_eq!(names, ["i"]);
+/// assert_eq!(
+///     skipped,
+///     "Skipped `g`, because it's unsafe\nSkipped `h`, because it's async\n",
+/// );
+/// ```
+fn find_bench_functions(code: &str) -> (Vec<&str>, String) {
+    let mut names = Vec::new();
+    let mut skipped = String::new();
+    for index in top_level_fn_indices(code) {
+        let signature = &code[(index + "fn ".len())..];
+        let params_start = match signature.find('(') {
+            Some(x) => x,
+            None => continue,
+        };
+        let name = signature[..params_start].trim();
+        // the qualifiers before `fn`, like `pub(crate) unsafe`
+        let qualifiers = code[..index].rsplit('\n').next().unwrap_or("");
+        let has_qualifier = |qualifier| qualifiers.split_whitespace().any(|word| word == qualifier);
+
+        // find the matching closing paren, parameter types can contain parens too
+        let mut depth = 0;
+        let params_end = signature[params_start..].find(|c| {
+            match c {
+                '(' => depth += 1,
+                ')' => depth -= 1,
+                _ => {}
+            }
+            depth == 0
+        });
+        let params_end = match params_end {
+            Some(x) => params_start + x,
+            None => continue,
+        };
+        let params = &signature[(params_start + 1)..params_end];
+        let return_type = signature[(params_end + 1)..]
+            .split(&['{', ';'][..])
+            .next()
+            .unwrap_or("")
+            .trim();
+
+        if has_qualifier("unsafe") {
+            skipped += &format!("Skipped `{}`, because it's unsafe\n", name);
+        } else if has_qualifier("async") {
+            skipped += &format!("Skipped `{}`, because it's async\n", name);
+        } else if name.contains('<') {
+            skipped += &format!("Skipped `{}`, because it's generic\n", name);
+        } else if !params.trim().is_empty() {
+            skipped += &format!("Skipped `{}`, because it has parameters\n", name);
+        } else if !matches!(
+            return_type.strip_prefix("->").map(str::trim),
+            None | Some("()")
+        ) {
+            skipped += &format!("Skipped `{}`, because it returns a value\n", name);
+        } else {
+            names.push(name);
+        }
+    }
+    (names, skipped)
 }
 
-pub fn fmt_help(args: &Args) -> Result<(), Error> {
-    let desc = "Format code using rustfmt";
-    generic_help(args, "fmt", desc, false, "code")
+/// Parse the `name: N iters per second` lines printed by the micro_bench harness
+///
+/// ```rust
+/// let stdout = "a: 1234 iters per second (810.4ns±12.0)\nb: 56 iters per second (17.9ms±0.1)\n";
+/// assert_eq!(parse_bench_iters_per_second(stdout), [("a", 1234.0), ("b", 56.0)]);
+/// assert_eq!(parse_bench_iters_per_second("thread 'main' panicked"), []);
+/// ```
+fn parse_bench_iters_per_second(stdout: &str) -> Vec<(&str, f64)> {
+    stdout
+        .lines()
+        .filter_map(|line| {
+            let (name, rest) = line.split_at(line.find(": ")?);
+            let iters = rest[2..]
+                .strip_suffix(")")?
+                .split(" iters per second")
+                .next()?;
+            Some((name, iters.parse().ok()?))
+        })
+        .collect()
 }
 
 pub fn micro_bench(args: &Args) -> Result<(), Error> {
@@ -620,13 +4557,20 @@ pub fn micro_bench(args: &Args) -> Result<(), Error> {
         "#![feature(test)] #[allow(unused_imports)] use std::hint::black_box;\n".to_owned();
 
     let user_input = crate::extract_code(args.body)?;
-    let black_box_hint = !user_input.contains("black_box");
-    code += user_input;
+    code += &user_input;
+
+    let (flags, mut flag_parse_errors) = parse_flags(args, &user_input);
 
     code += r#"
 fn bench(functions: &[(&str, fn())]) {
     const CHUNK_SIZE: usize = 10000;
 
+    // None if there are no samples
+    fn percentile(sorted_times: &[f64], fraction: f64) -> Option<f64> {
+        let last_index = sorted_times.len().checked_sub(1)?;
+        sorted_times.get((last_index as f64 * fraction).round() as usize).copied()
+    }
+
     // Warm up
     for (_, function) in functions.iter() {
         for _ in 0..CHUNK_SIZE {
@@ -637,7 +4581,9 @@ fn bench(functions: &[(&str, fn())]) {
     let mut functions_chunk_times = functions.iter().map(|_| Vec::new()).collect::<Vec<_>>();
 
     let start = std::time::Instant::now();
-    while (std::time::Instant::now() - start).as_secs() < 5 {
+    while (std::time::Instant::now() - start).as_secs() < "#;
+    code += &flags.bench_time.to_string();
+    code += r#" {
         for (chunk_times, (_, function)) in functions_chunk_times.iter_mut().zip(functions) {
             let start = std::time::Instant::now();
             for _ in 0..CHUNK_SIZE {
@@ -647,8 +4593,10 @@ fn bench(functions: &[(&str, fn())]) {
         }
     }
 
+    let mut mean_times = Vec::new();
     for (chunk_times, (function_name, _)) in functions_chunk_times.iter().zip(functions) {
         let mean_time: f64 = chunk_times.iter().sum::<f64>() / chunk_times.len() as f64;
+        mean_times.push(mean_time);
         let standard_deviation: f64 = f64::sqrt(
             chunk_times
                 .iter()
@@ -664,75 +4612,304 @@ fn bench(functions: &[(&str, fn())]) {
             mean_time * 1_000_000_000.0,
             standard_deviation * 1_000_000_000.0,
         );
+
+        // Unlike the mean, these aren't skewed by outliers like scheduler hiccups
+        let mut sorted_times = chunk_times.clone();
+        sorted_times.sort_by(f64::total_cmp);
+        if let (Some(median), Some(p95)) =
+            (percentile(&sorted_times, 0.5), percentile(&sorted_times, 0.95))
+        {
+            println!(
+                "    median {:.1}ns, p95 {:.1}ns",
+                median * 1_000_000_000.0,
+                p95 * 1_000_000_000.0,
+            );
+        }
+    }
+
+    if functions.len() > 1 {
+        let (fastest_index, fastest_time) = mean_times
+            .iter()
+            .copied()
+            .enumerate()
+            .fold((0, f64::INFINITY), |a, b| if b.1 < a.1 { b } else { a });
+
+        println!();
+        for (i, (mean_time, (function_name, _))) in mean_times.iter().zip(functions).enumerate() {
+            if i != fastest_index {
+                println!(
+                    "{} is {:.2}x faster than {}",
+                    functions[fastest_index].0,
+                    mean_time / fastest_time,
+                    function_name,
+                );
+            }
+        }
     }
 }
 
 fn main() {
 "#;
 
-    let pub_fn_indices = user_input.match_indices("pub fn ");
-    if pub_fn_indices.clone().count() == 0 {
+    let (function_names, skipped_functions) = find_bench_functions(&user_input);
+    flag_parse_errors += &skipped_functions;
+    if function_names.is_empty() {
         return api::send_reply(
             args,
-            "No public functions found for benchmarking :thinking:",
+            &format!(
+                "{}No functions found for benchmarking :thinking:",
+                flag_parse_errors
+            ),
         );
     }
 
     code += "bench(&[";
-    for (index, _) in pub_fn_indices {
-        let function_name_start = index + "pub fn ".len();
-        let function_name_end = match user_input[function_name_start..].find('(') {
-            Some(x) => x + function_name_start,
-            None => continue,
-        };
-        let function_name = user_input[function_name_start..function_name_end].trim();
-
+    for function_name in function_names {
         code += &format!("(\"{0}\", {0}), ", function_name);
     }
     code += "]);\n}\n";
 
-    let (flags, mut flag_parse_errors) = parse_flags(args);
-    let mut result: PlayResult = args
-        .http
-        .post("https://play.rust-lang.org/execute")
-        .json(&PlaygroundRequest {
+    count_playground_request(args);
+    let response = post_with_retry(
+        args.http
+            .post("https://play.rust-lang.org/execute")
+            .timeout(BENCH_TIMEOUT),
+        &PlaygroundRequest {
             code: &code,
             channel: Channel::Nightly, // has to be, for black_box
-            crate_type: if code.contains("fn main") {
-                CrateType::Binary
-            } else {
-                CrateType::Library
-            },
+            crate_type: crate_type(&flags, &code),
             edition: flags.edition,
             mode: Mode::Release, // benchmarks on debug don't make sense
             tests: false,
-        })
-        .send()?
-        .json()?;
+            backtrace: flags.backtrace,
+        },
+    )?;
+    let status = response.status();
+    let result: PlayResult = parse_playground_response(status, &response.text()?)?;
 
-    format_play_eval_stderr(&mut result);
+    let result = format_play_eval_stderr(result);
 
-    if black_box_hint {
+    // More than a billion iterations per second means the function takes less than a nanosecond,
+    // which only happens if there's practically nothing left to compute
+    const SUSPICIOUS_ITERS_PER_SECOND: f64 = 1e9;
+    let iters_per_second = parse_bench_iters_per_second(&result.stdout);
+    let optimized_away = iters_per_second
+        .iter()
+        .filter(|&&(_, iters)| iters > SUSPICIOUS_ITERS_PER_SECOND)
+        .map(|&(name, _)| format!("`{}`", name))
+        .collect::<Vec<_>>();
+    if !optimized_away.is_empty() {
+        flag_parse_errors += &format!(
+            "Hint: {} ran suspiciously fast and was probably optimized out. Use the black_box \
+            function to prevent that\n",
+            optimized_away.join(", ")
+        );
+    } else if iters_per_second.is_empty() && !user_input.contains("black_box") {
         flag_parse_errors +=
             "Hint: use the black_box function to prevent computations from being optimized out\n";
     }
-    send_reply(args, result, &code, &flags, &flag_parse_errors)
+    send_reply(args, result, &code, &flags, &flag_parse_errors)?;
+    send_generated_source(args, &flags, &code)
 }
 
 pub fn micro_bench_help(args: &Args) -> Result<(), Error> {
     let desc =
-        "Benchmark small snippets of code by running them repeatedly. The public function snippets are run \
+        "Benchmark small snippets of code by running them repeatedly. The top-level functions are run \
         in chunks, interleaved: Snippet A is ran 10000 times, then snippet B is ran 10000 times, \
-        then snippet A again, and so on until a certain time has passed. After that, the \
-        measuremants are averaged and the standard deviation is calculated for each";
+        then snippet A again, and so on until a certain time has passed (5 seconds by default, \
+        configurable from 1 to 15 seconds with bench_time=). After that, the \
+        measuremants are averaged and the standard deviation is calculated for each, along with the \
+        median and the 95th percentile. If a snippet \
+        runs so fast that it was probably optimized away, you'll get a hint to use black_box";
     generic_help(
         args,
         "microbench",
         desc,
         false,
         "
-pub fn snippet_a() { /* code */ }
-pub fn snippet_b() { /* code */ }
+fn snippet_a() { /* code */ }
+fn snippet_b() { /* code */ }
 ",
     )
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn message_params_take_precedence_over_inline_flags() {
+        let code = "// edition: 2018\n// channel: beta\nfn main() {}";
+        let message_params = [("edition", "2015")].iter().copied().collect();
+        let (params, errors) = flag_params(&message_params, code);
+        assert_eq!(params["edition"], "2015");
+        assert_eq!(params["channel"], "beta");
+        assert_eq!(errors, "");
+    }
+
+    #[test]
+    fn inline_flags_are_only_read_from_leading_comments() {
+        let code = "// note: not a flag\nfn main() {}\n// run: false";
+        let (params, errors) = flag_params(&HashMap::new(), code);
+        assert!(params.is_empty());
+        assert_eq!(errors, "");
+    }
+
+    #[test]
+    fn filter_tests_disables_unmatched_tests() {
+        let code = "#[test]\nfn parses() {}\n#[test] fn prints() {}\nfn helper() {}";
+        let (filtered, matched, filtered_out) = filter_tests(code, "pars");
+        assert_eq!(
+            filtered,
+            "#[test]\nfn parses() {}\n#[cfg(any())] fn prints() {}\nfn helper() {}",
+        );
+        assert_eq!((matched, filtered_out), (1, 1));
+        assert_eq!(filter_tests(code, "p").1, 2);
+        assert_eq!(filter_tests(code, "nothing").1, 0);
+    }
+
+    #[test]
+    fn miri_summary_classifies_the_verdict() {
+        let stderr = "Running `cargo-miri runner`\nerror: Undefined Behavior: using uninitialized \
+            data, but this operation requires initialized memory";
+        assert_eq!(
+            miri_summary(stderr, false).as_deref(),
+            Some(
+                "⚠ Miri detected undefined behavior: using uninitialized data, but this operation \
+                requires initialized memory"
+            ),
+        );
+
+        let stderr =
+            "Running `cargo-miri runner`\nerror: memory leaked: alloc1 (Rust heap, size: 4)";
+        assert_eq!(
+            miri_summary(stderr, false).as_deref(),
+            Some("⚠ Miri detected a memory leak")
+        );
+
+        let stderr = "Running `cargo-miri runner`\nerror: unsupported operation: can't call \
+            foreign function `socket` on OS `linux`";
+        assert_eq!(
+            miri_summary(stderr, false).as_deref(),
+            Some("⚠ Miri doesn't support an operation of the program, so it couldn't check all of it"),
+        );
+
+        assert_eq!(
+            miri_summary("Running `cargo-miri runner`", true).as_deref(),
+            Some("No undefined behavior detected by Miri"),
+        );
+        assert_eq!(
+            miri_summary("error[E0425]: cannot find value `x`", false),
+            None
+        );
+    }
+
+    #[test]
+    fn find_bench_functions_checks_signatures() {
+        let code = "pub fn a() {}\nfn b() {}\nfn c(x: u32) {}\nfn d() -> u32 { 0 }\n\
+            fn e<T>() {}\nunsafe fn f() {}\nasync fn g() {}\nfn h() -> () {}";
+        let (names, skipped) = find_bench_functions(code);
+        assert_eq!(names, ["a", "b", "h"]);
+        assert_eq!(
+            skipped,
+            "Skipped `c`, because it has parameters\n\
+            Skipped `d`, because it returns a value\n\
+            Skipped `e<T>`, because it's generic\n\
+            Skipped `f`, because it's unsafe\n\
+            Skipped `g`, because it's async\n",
+        );
+    }
+
+    #[test]
+    fn find_bench_functions_skips_nested_functions() {
+        let code = "fn outer() {\n    fn inner() {}\n    let s = \"fn fake() {}\";\n}";
+        assert_eq!(find_bench_functions(code).0, ["outer"]);
+    }
+
+    #[test]
+    fn timeout_message_is_rewritten() {
+        let stderr = "counting...\n/playground/tools/entrypoint.sh: line 11:     8 Killed    \
+            timeout --signal=KILL ${timeout} \"$@\"";
+        assert_eq!(
+            rewrite_timeout_message(stderr),
+            "counting...\nYour program exceeded the execution time limit (likely an infinite loop \
+            or too much work).",
+        );
+
+        let stderr = "thread 'main' panicked at 'oops', src/main.rs:1:13";
+        assert!(matches!(rewrite_timeout_message(stderr), Cow::Borrowed(s) if s == stderr));
+    }
+
+    #[test]
+    fn env_var_comments_are_extracted() {
+        let mut errors = String::new();
+        let code = "// env: RUST_LOG=debug\n//env:GREETING=hello world\nlet x = 1;";
+        let (code, env_vars) = extract_env_vars(code, &mut errors);
+        assert_eq!(code, "\n\nlet x = 1;");
+        assert_eq!(
+            env_vars,
+            [("RUST_LOG", "debug"), ("GREETING", "hello world")]
+        );
+        assert_eq!(errors, "");
+
+        let (_, env_vars) = extract_env_vars("// env: NO_VALUE\n// env: =x", &mut errors);
+        assert!(env_vars.is_empty());
+        assert_eq!(errors.lines().count(), 2);
+    }
+
+    #[test]
+    fn env_vars_are_set_at_the_start_of_main() {
+        assert_eq!(
+            inject_env_vars("fn main() {\n}", &[("KEY", "a \"b\"")], Edition::E2021).as_deref(),
+            Some("fn main() { std::env::set_var(\"KEY\", \"a \\\"b\\\"\");\n}"),
+        );
+        assert_eq!(
+            inject_env_vars("fn main() {}", &[("A", "1")], Edition::E2024).as_deref(),
+            Some("fn main() { unsafe { std::env::set_var(\"A\", \"1\") }}"),
+        );
+        assert_eq!(
+            inject_env_vars("pub fn f() {}", &[("A", "1")], Edition::E2021),
+            None
+        );
+    }
+
+    #[test]
+    fn random_channel_is_deterministic_and_covers_all_channels() {
+        assert_eq!(
+            random_channel(42).to_string(),
+            random_channel(42).to_string()
+        );
+        let picked = (0..30)
+            .map(|seed| random_channel(seed).to_string())
+            .collect::<Vec<_>>();
+        for channel in &["stable", "beta", "nightly"] {
+            assert!(picked.iter().any(|picked| picked == channel));
+        }
+    }
+
+    #[test]
+    fn required_edition_ignores_self_defined_names_and_root_modules() {
+        let code = "trait Collect { fn from_iter(v: Vec<u8>) -> Self; }\nfn main() {}";
+        assert_eq!(required_edition(code), None);
+        let code = "fn main() {\n    mod inner { pub fn f() {} }\n    use inner::f;\n    f();\n}";
+        assert_eq!(
+            required_edition(code).map(|(edition, _)| edition),
+            Some(Edition::E2018)
+        );
+        let code = "mod util;\nuse util::f;\nfn main() { f(); }";
+        assert_eq!(required_edition(code), None);
+    }
+
+    #[test]
+    fn needs_executor_only_checks_top_level_code() {
+        let code = "mod m {\n    pub async fn f() -> u8 { g().await }\n}\nlet x = m::f().await;";
+        assert!(needs_executor(code));
+        let code = "trait T { fn f(&self); }\nlet s = \"a.await\";\nlet r = r.await_all();";
+        assert!(!needs_executor(code));
+        let (code, _) = wrap_code(
+            "async fn f() {}\nfn g() { block_on(async { f().await }) }",
+            ResultHandling::None,
+        );
+        assert!(!code.contains("ThreadWaker"));
+    }
+}